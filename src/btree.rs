@@ -1,3 +1,40 @@
+//! B+trees of time series values keyed by asset, date and timestamp.
+//!
+//! The file-backed tree in [`file`] is the supported one, and its main types are re-exported here. The in-memory tree
+//! in [`mem`] is an experiment that stores plain `u32` keys and values.
+//!
+//! ```
+//! use findb::btree::{AssetId, BTree, Date, Key, Query, Timestamp};
+//! use std::fs::File;
+//!
+//! let path = std::env::temp_dir().join("findb-btree-doctest.db");
+//! let path = path.to_str().unwrap();
+//! let mut rows = (1..=5).map(|day| {
+//!     let key = Key::new(AssetId(1), Date::from_ymd(2020, 10, day), Timestamp(1));
+//!     (key, day as f32)
+//! });
+//! BTree::write_from_iterator(path, 256, &mut rows)?;
+//!
+//! let mut btree = BTree::from_file(File::open(path)?, 4)?;
+//! let query = Query::new(0, AssetId(1), Date(20201002), Date(20201004), Timestamp(1));
+//! let mut count = 0;
+//! for result in btree.query(query)? {
+//!     result?;
+//!     count += 1;
+//! }
+//! assert_eq!(3, count);
+//! # std::fs::remove_file(path)?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
 pub mod cache;
 pub mod file;
-mod mem;
+pub mod mem;
+mod node;
+
+pub use cache::CacheStats;
+pub use file::{
+    check_page_size, effective_ranges, read_csv, read_csv_with_format, AssetId, BTree, CsvFormat,
+    Cursor, Date, DictionaryCode, EffectiveRange, FillStats, Key, Query, QueryResult,
+    QueryResultIterator, Timestamp, TimestampBound, Value, ValueCodec, BLOCK_SIZE, FORMAT_VERSION,
+};
@@ -1,104 +1,210 @@
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{Error, Read, Seek, SeekFrom};
 
-struct Clock {
-    clock: Vec<u8>,
-    slots: usize,
-    slot_index: usize,
-}
+/// A bit per slot.
+struct SlotBits(Vec<u8>);
 
-impl Clock {
-    fn new(slots: usize) -> Clock {
-        let mut clock = Vec::with_capacity(slots / 8 + if slots % 8 == 0 { 0 } else { 1 });
-        for _ in 0..clock.capacity() {
-            clock.push(0);
-        }
-        Clock {
-            clock,
-            slots,
-            slot_index: 0,
-        }
+impl SlotBits {
+    fn new(slots: usize) -> SlotBits {
+        SlotBits(vec![0; slots.div_ceil(8)])
     }
 
     fn set(&mut self, slot: usize) {
         let byte = slot / 8;
         let bit = slot % 8;
         let mask = 1 << bit;
-        self.clock[byte] = self.clock[byte] | mask;
+        self.0[byte] |= mask;
     }
 
     fn unset(&mut self, slot: usize) {
         let byte = slot / 8;
         let bit = slot % 8;
         let mask = 1 << bit;
-        self.clock[byte] = self.clock[byte] & !mask;
+        self.0[byte] &= !mask;
     }
 
     fn test(&self, slot: usize) -> bool {
         let byte = slot / 8;
         let bit = slot % 8;
         let mask = 1 << bit;
-        self.clock[byte] & mask != 0
+        self.0[byte] & mask != 0
+    }
+}
+
+struct Clock {
+    clock: SlotBits,
+    pinned: SlotBits,
+    slots: usize,
+    slot_index: usize,
+}
+
+impl Clock {
+    fn new(slots: usize) -> Clock {
+        Clock {
+            clock: SlotBits::new(slots),
+            pinned: SlotBits::new(slots),
+            slots,
+            slot_index: 0,
+        }
+    }
+
+    fn set(&mut self, slot: usize) {
+        self.clock.set(slot);
+    }
+
+    fn pin(&mut self, slot: usize) {
+        self.pinned.set(slot);
+    }
+
+    fn unpin(&mut self, slot: usize) {
+        self.pinned.unset(slot);
+    }
+
+    fn is_referenced(&self, slot: usize) -> bool {
+        self.clock.test(slot)
+    }
+
+    fn is_pinned(&self, slot: usize) -> bool {
+        self.pinned.test(slot)
     }
 
     fn advance(&mut self) {
         self.slot_index = (self.slot_index + 1) % self.slots;
     }
 
-    fn evict(&mut self) -> usize {
-        while self.test(self.slot_index) {
-            self.unset(self.slot_index);
+    /// Picks the next slot that's neither pinned nor recently referenced, clearing the referenced bits it passes.
+    /// After two passes over the slots every unpinned slot has been cleared, so if none has turned up by then, they're
+    /// all pinned and there's nothing to evict.
+    fn evict(&mut self) -> Option<usize> {
+        for _ in 0..(2 * self.slots) {
+            let slot = self.slot_index;
             self.advance();
+            if self.pinned.test(slot) {
+                continue;
+            }
+            if self.clock.test(slot) {
+                self.clock.unset(slot);
+            } else {
+                return Some(slot);
+            }
         }
-
-        let res = self.slot_index;
-        self.advance();
-        res
+        None
     }
 }
 
+/// Counts of the loads that found their page in the cache and those that had to read it from the file.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Anything pages can be read from, such as a file or a buffer in memory.
+pub trait PageSource: Read + Seek {}
+
+impl<T: Read + Seek> PageSource for T {}
+
 pub struct PageCache {
-    file: File,
+    file: Box<dyn PageSource>,
     page_size: usize,
     pages: usize,
     header_bytes: u64,
+    /// Where each page starts in a file of compressed pages, followed by where the last one ends.
+    #[cfg(feature = "compressed")]
+    page_offsets: Option<Vec<u64>>,
+    /// A file mapped into memory, whose pages are served from the mapping rather than read into the buffer.
+    #[cfg(feature = "mmap")]
+    mmap: Option<memmap2::Mmap>,
     buf: Vec<u8>,
     clock: Clock,
     page_map: HashMap<usize, usize>,
     slot_map: HashMap<usize, usize>,
+    stats: CacheStats,
 }
 
 impl PageCache {
-    pub fn new(file: File, page_size: usize, pages: usize, header_bytes: u64) -> PageCache {
-        let mut buf = Vec::with_capacity(page_size * pages);
-        for _ in 0..buf.capacity() {
-            buf.push(0);
-        }
+    /// Creates a cache holding up to `pages` pages of the file. A cache of zero pages caches nothing, re-reading every
+    /// page it loads into a single scratch page.
+    pub fn new<S: PageSource + 'static>(
+        file: S,
+        page_size: usize,
+        pages: usize,
+        header_bytes: u64,
+    ) -> PageCache {
+        let buf = vec![0; page_size * pages.max(1)];
 
         PageCache {
-            file,
+            file: Box::new(file),
             page_size,
             pages,
             header_bytes,
+            #[cfg(feature = "compressed")]
+            page_offsets: None,
+            #[cfg(feature = "mmap")]
+            mmap: None,
             buf,
             clock: Clock::new(pages),
             page_map: HashMap::new(),
             slot_map: HashMap::new(),
+            stats: CacheStats::default(),
+        }
+    }
+
+    /// Reads a file whose pages are each compressed with zstd, given where each page starts in the file followed by
+    /// where the last one ends. Pages are decompressed as they're read, and the cache holds them decompressed.
+    #[cfg(feature = "compressed")]
+    pub fn with_page_offsets(mut self, page_offsets: Vec<u64>) -> PageCache {
+        self.page_offsets = Some(page_offsets);
+        self
+    }
+
+    /// Serves the pages of a file mapped into memory straight from the mapping, leaving it to the OS to keep them in
+    /// memory. There's no buffer of pages, so none can be pinned and the cache can't be resized.
+    #[cfg(feature = "mmap")]
+    pub fn mapped(mmap: memmap2::Mmap, page_size: usize, header_bytes: u64) -> PageCache {
+        PageCache {
+            // Nothing is read through the file, since every page is in the mapping.
+            file: Box::new(std::io::empty()),
+            page_size,
+            pages: 0,
+            header_bytes,
+            #[cfg(feature = "compressed")]
+            page_offsets: None,
+            mmap: Some(mmap),
+            buf: Vec::new(),
+            clock: Clock::new(0),
+            page_map: HashMap::new(),
+            slot_map: HashMap::new(),
+            stats: CacheStats::default(),
         }
     }
 
     pub fn load(&mut self, page_number: usize) -> std::io::Result<&[u8]> {
+        #[cfg(feature = "mmap")]
+        if self.mmap.is_some() {
+            self.stats.misses += 1;
+            return self.mapped_page(page_number);
+        }
+
+        if self.pages == 0 {
+            self.stats.misses += 1;
+            return self.read_page(0, page_number);
+        }
+
         match self.page_map.get(&page_number) {
             Some(slot_number) => {
                 let num = *slot_number;
-                self.page_from_slot(num, false)
+                self.stats.hits += 1;
+                self.page_from_slot(num, None)
             }
             None => {
+                self.stats.misses += 1;
                 let slot_number = if self.page_map.len() < self.pages {
                     self.page_map.len()
                 } else {
-                    let slot_number = self.clock.evict();
+                    let slot_number = self.clock.evict().ok_or_else(|| {
+                        Error::other(format!("All {} pages in the cache are pinned", self.pages))
+                    })?;
                     if let Some(evicted_page_num) = self.slot_map.get(&slot_number) {
                         self.page_map.remove(evicted_page_num);
                     }
@@ -107,22 +213,269 @@ impl PageCache {
 
                 self.page_map.insert(page_number, slot_number);
                 self.slot_map.insert(slot_number, page_number);
-                self.page_from_slot(slot_number, true)
+                self.page_from_slot(slot_number, Some(page_number))
             }
         }
     }
 
-    fn page_from_slot(&mut self, slot_number: usize, read: bool) -> std::io::Result<&[u8]> {
-        let page_start = slot_number * self.page_size;
-        let page_end = (slot_number + 1) * self.page_size;
-        let buf = &mut self.buf[page_start..page_end];
-        if read {
-            let offset = (page_start as u64) + self.header_bytes;
-            self.file.seek(SeekFrom::Start(offset))?;
-            self.file.read(buf)?;
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// The size of the buffer the cache holds its pages in.
+    pub fn buffer_bytes(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Loads the page and keeps it in the cache until it's unpinned. Fails if the cache holds no pages.
+    pub fn pin(&mut self, page_number: usize) -> std::io::Result<()> {
+        if self.pages == 0 {
+            return Err(Error::other("A cache of zero pages can't pin a page"));
+        }
+        self.load(page_number)?;
+        self.clock.pin(self.page_map[&page_number]);
+        Ok(())
+    }
+
+    /// Lets the page be evicted again. Does nothing if the page isn't in the cache.
+    pub fn unpin(&mut self, page_number: usize) {
+        if let Some(&slot_number) = self.page_map.get(&page_number) {
+            self.clock.unpin(slot_number);
+        }
+    }
+
+    /// Changes the number of pages the cache holds. Growing keeps every page in the cache. Shrinking keeps the pinned
+    /// pages first, then the recently referenced ones, then whichever others fit, and fails if the pinned pages alone
+    /// don't fit.
+    pub fn resize(&mut self, pages: usize) -> std::io::Result<()> {
+        #[cfg(feature = "mmap")]
+        if self.mmap.is_some() {
+            return Err(Error::other("A mapped file has no cache to resize"));
+        }
+
+        let mut resident: Vec<(usize, usize)> = self
+            .page_map
+            .iter()
+            .map(|(&page_number, &slot_number)| (page_number, slot_number))
+            .collect();
+        let pinned = resident
+            .iter()
+            .filter(|(_, slot_number)| self.clock.is_pinned(*slot_number))
+            .count();
+        if pinned > pages {
+            return Err(Error::other(format!(
+                "Can't shrink the cache to {} pages with {} pages pinned",
+                pages, pinned
+            )));
+        }
+        resident.sort_by_key(|&(page_number, slot_number)| {
+            (
+                !self.clock.is_pinned(slot_number),
+                !self.clock.is_referenced(slot_number),
+                page_number,
+            )
+        });
+        resident.truncate(pages);
+
+        let mut buf = vec![0; self.page_size * pages.max(1)];
+        let mut clock = Clock::new(pages);
+        let mut page_map = HashMap::new();
+        let mut slot_map = HashMap::new();
+        for (new_slot_number, &(page_number, slot_number)) in resident.iter().enumerate() {
+            let old_start = slot_number * self.page_size;
+            let new_start = new_slot_number * self.page_size;
+            buf[new_start..(new_start + self.page_size)]
+                .copy_from_slice(&self.buf[old_start..(old_start + self.page_size)]);
+            if self.clock.is_referenced(slot_number) {
+                clock.set(new_slot_number);
+            }
+            if self.clock.is_pinned(slot_number) {
+                clock.pin(new_slot_number);
+            }
+            page_map.insert(page_number, new_slot_number);
+            slot_map.insert(new_slot_number, page_number);
+        }
+
+        self.pages = pages;
+        self.buf = buf;
+        self.clock = clock;
+        self.page_map = page_map;
+        self.slot_map = slot_map;
+        Ok(())
+    }
+
+    /// Returns the page held in the given slot, first reading `page_number` from the file into the slot if provided.
+    fn page_from_slot(
+        &mut self,
+        slot_number: usize,
+        page_number: Option<usize>,
+    ) -> std::io::Result<&[u8]> {
+        if let Some(page_number) = page_number {
+            self.read_page(slot_number, page_number)?;
         }
 
         self.clock.set(slot_number);
+        let page_start = slot_number * self.page_size;
+        Ok(&self.buf[page_start..(page_start + self.page_size)])
+    }
+
+    /// Returns a page of a mapped file from the mapping.
+    #[cfg(feature = "mmap")]
+    fn mapped_page(&self, page_number: usize) -> std::io::Result<&[u8]> {
+        let start = page_offset(page_number, self.page_size, self.header_bytes) as usize;
+        self.mmap
+            .as_ref()
+            .and_then(|mmap| mmap.get(start..start + self.page_size))
+            .ok_or_else(|| {
+                Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    format!("There's no page {} in the file", page_number),
+                )
+            })
+    }
+
+    /// Reads a page from the file into the given slot, bypassing the clock.
+    fn read_page(&mut self, slot_number: usize, page_number: usize) -> std::io::Result<&[u8]> {
+        let page_start = slot_number * self.page_size;
+        let buf = &mut self.buf[page_start..(page_start + self.page_size)];
+        #[cfg(feature = "compressed")]
+        if let Some(page_offsets) = &self.page_offsets {
+            let (start, end) = match page_offsets.get(page_number..=page_number + 1) {
+                Some(&[start, end]) => (start, end),
+                _ => {
+                    return Err(Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        format!("There's no page {} in the file", page_number),
+                    ))
+                }
+            };
+            let mut compressed = vec![0; (end - start) as usize];
+            self.file.seek(SeekFrom::Start(start))?;
+            self.file.read_exact(&mut compressed)?;
+            let size = zstd::bulk::decompress_to_buffer(&compressed, buf)?;
+            if size != self.page_size {
+                return Err(Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "Page {} decompressed to {} bytes rather than {}",
+                        page_number, size, self.page_size
+                    ),
+                ));
+            }
+            return Ok(buf);
+        }
+        let offset = page_offset(page_number, self.page_size, self.header_bytes);
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(buf)?;
         Ok(buf)
     }
 }
+
+/// The position of a page in the file. The sum is taken in 64 bits, since a file can hold more bytes of pages than a
+/// 32 bit `usize` can count.
+fn page_offset(page_number: usize, page_size: usize, header_bytes: u64) -> u64 {
+    page_number as u64 * page_size as u64 + header_bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::btree::cache::{page_offset, CacheStats, Clock, PageCache};
+    use std::fs::{self, File};
+    use std::io::ErrorKind;
+
+    #[test]
+    fn test_evict_referenced() {
+        let mut clock = Clock::new(3);
+        for slot in 0..3 {
+            clock.set(slot);
+        }
+        assert_eq!(Some(0), clock.evict());
+        assert_eq!(Some(1), clock.evict());
+        clock.set(2);
+        assert_eq!(Some(0), clock.evict());
+    }
+
+    #[test]
+    fn test_evict_pinned() {
+        let mut clock = Clock::new(3);
+        for slot in 0..3 {
+            clock.set(slot);
+            clock.pin(slot);
+        }
+        assert_eq!(None, clock.evict());
+
+        clock.unpin(1);
+        assert_eq!(Some(1), clock.evict());
+    }
+
+    #[test]
+    fn test_page_offset() {
+        assert_eq!(32, page_offset(0, 4096, 32));
+        assert_eq!(4096 + 32, page_offset(1, 4096, 32));
+
+        // Two million 4 KiB pages are past 4 GiB, which overflows when the offset is taken in 32 bits.
+        let page_number = 2_000_000;
+        assert_eq!(None, (page_number as u32).checked_mul(4096));
+        assert_eq!(8_192_000_032, page_offset(page_number, 4096, 32));
+        assert_eq!(
+            u32::MAX as u64 * 1024 + 32,
+            page_offset(u32::MAX as usize, 1024, 32)
+        );
+    }
+
+    #[test]
+    fn test_pin_every_page() {
+        let path = "test_pin_every_page.db";
+        let contents: Vec<u8> = (0..4u8).flat_map(|page| [page; 8]).collect();
+        fs::write(path, contents).unwrap();
+
+        let mut cache = PageCache::new(File::open(path).unwrap(), 8, 2, 0);
+        cache.pin(0).unwrap();
+        cache.pin(1).unwrap();
+        let error = cache.load(2).unwrap_err();
+        assert_eq!(ErrorKind::Other, error.kind());
+        assert_eq!(&[0; 8], cache.load(0).unwrap());
+
+        cache.unpin(0);
+        assert_eq!(&[2; 8], cache.load(2).unwrap());
+        assert_eq!(&[3; 8], cache.load(3).unwrap());
+        assert_eq!(&[1; 8], cache.load(1).unwrap());
+
+        let mut uncached = PageCache::new(File::open(path).unwrap(), 8, 0, 0);
+        assert!(uncached.pin(0).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_resize() {
+        let path = "test_resize.db";
+        let contents: Vec<u8> = (0..6u8).flat_map(|page| [page; 8]).collect();
+        fs::write(path, contents).unwrap();
+
+        let mut cache = PageCache::new(File::open(path).unwrap(), 8, 2, 0);
+        cache.load(0).unwrap();
+        cache.load(1).unwrap();
+        cache.load(0).unwrap();
+        assert_eq!(CacheStats { hits: 1, misses: 2 }, cache.stats());
+        cache.resize(4).unwrap();
+        assert!(cache.page_map.contains_key(&0));
+        assert!(cache.page_map.contains_key(&1));
+        for page in 0..4u8 {
+            assert_eq!(&[page; 8], cache.load(page as usize).unwrap());
+        }
+        assert_eq!(4, cache.page_map.len());
+
+        cache.pin(3).unwrap();
+        cache.resize(1).unwrap();
+        assert_eq!(vec![&3], cache.page_map.keys().collect::<Vec<_>>());
+        assert!(cache.resize(0).is_err());
+        cache.unpin(3);
+        assert_eq!(&[5; 8], cache.load(5).unwrap());
+        assert_eq!(&[3; 8], cache.load(3).unwrap());
+
+        cache.resize(0).unwrap();
+        assert!(cache.page_map.is_empty());
+        assert_eq!(&[4; 8], cache.load(4).unwrap());
+        fs::remove_file(path).unwrap();
+    }
+}
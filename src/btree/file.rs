@@ -1,23 +1,141 @@
-use crate::btree::cache::PageCache;
-use std::cmp::{min, Ordering};
+use crate::btree::cache::{CacheStats, PageCache};
+use crate::btree::node::{self, Direction, LeafNode, Step};
+use crate::date::Calendar;
+use itertools::Itertools;
+use std::cmp::min;
+use std::collections::{BTreeMap, HashSet};
 use std::convert::TryInto;
+use std::fmt::Debug;
 use std::fs::File;
-use std::io::prelude::*;
-use std::io::{BufReader, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
 use std::mem::size_of;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
 
-/// Super simple on-disk btree implementation with fixed-size keys and a single floating point value contained  
-/// inside the node itself rather than in a separate file.
+// Super simple on-disk btree implementation with fixed-size keys and a single fixed-size value, a float unless
+// another `ValueCodec` is chosen, contained inside the node itself rather than in a separate file.
+
+/// Logs a step taken by a query's iterator at the trace level with the `trace` feature, and compiles to nothing
+/// without it.
+macro_rules! trace_iterate {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "trace")]
+        log::trace!($($arg)*);
+    };
+}
+
+/// Defines a wrapper around a raw integer, so that the different kinds of integer in a key can't be swapped for one
+/// another by accident. The raw integers are still used to read and write pages.
+macro_rules! key_field_type {
+    ($(#[$attr:meta])* $name:ident($raw:ty)) => {
+        $(#[$attr])*
+        #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+        pub struct $name(pub $raw);
+
+        impl From<$raw> for $name {
+            fn from(raw: $raw) -> $name {
+                $name(raw)
+            }
+        }
+
+        impl From<$name> for $raw {
+            fn from(field: $name) -> $raw {
+                field.0
+            }
+        }
+    };
+}
+
+key_field_type!(AssetId(u32));
+key_field_type!(
+    /// A date packed into an integer as YYYYMMDD.
+    Date(u32)
+);
+key_field_type!(
+    /// Time since the Unix epoch, in seconds or in finer units such as milliseconds, as long as a file sticks to one.
+    Timestamp(u64)
+);
 
-pub type AssetId = u32;
-pub type Date = u32;
-pub type Timestamp = u32;
 pub type PageNumber = u32;
 pub type Value = f32;
+pub type EffTimestamp = u64;
 const U32_SIZE: usize = size_of::<u32>();
+const U64_SIZE: usize = size_of::<u64>();
 
-#[derive(PartialEq, PartialOrd, Debug)]
+/// Encodes the values stored in a file's leaves, each of which takes up the same number of bytes. The codec's id and
+/// width are recorded in the file header, so a file can only be read back with the codec it was written with.
+pub trait ValueCodec: Copy + Debug {
+    const ID: u32;
+    const WIDTH: usize;
+
+    fn encode(self, buf: &mut [u8]);
+
+    fn decode(buf: &[u8]) -> Self;
+}
+
+impl ValueCodec for f32 {
+    const ID: u32 = 0;
+    const WIDTH: usize = size_of::<f32>();
+
+    fn encode(self, buf: &mut [u8]) {
+        write_f32(buf, self)
+    }
+
+    fn decode(buf: &[u8]) -> f32 {
+        read_f32(buf)
+    }
+}
+
+impl ValueCodec for f64 {
+    const ID: u32 = 1;
+    const WIDTH: usize = size_of::<f64>();
+
+    fn encode(self, buf: &mut [u8]) {
+        buf[0..Self::WIDTH].copy_from_slice(&self.to_be_bytes()[..])
+    }
+
+    fn decode(buf: &[u8]) -> f64 {
+        f64::from_be_bytes(buf[0..Self::WIDTH].try_into().unwrap())
+    }
+}
+
+impl ValueCodec for i32 {
+    const ID: u32 = 2;
+    const WIDTH: usize = size_of::<i32>();
+
+    fn encode(self, buf: &mut [u8]) {
+        buf[0..Self::WIDTH].copy_from_slice(&self.to_be_bytes()[..])
+    }
+
+    fn decode(buf: &[u8]) -> i32 {
+        i32::from_be_bytes(buf[0..Self::WIDTH].try_into().unwrap())
+    }
+}
+
+/// A categorical value, such as a sector, stored as its code in a dictionary kept by the caller.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct DictionaryCode(pub u16);
+
+impl ValueCodec for DictionaryCode {
+    const ID: u32 = 3;
+    const WIDTH: usize = size_of::<u16>();
+
+    fn encode(self, buf: &mut [u8]) {
+        buf[0..Self::WIDTH].copy_from_slice(&self.0.to_be_bytes()[..])
+    }
+
+    fn decode(buf: &[u8]) -> DictionaryCode {
+        DictionaryCode(u16::from_be_bytes(buf[0..Self::WIDTH].try_into().unwrap()))
+    }
+}
+
+/// Formats the encoded value at the start of the buffer for `BTree::print`.
+fn format_value<V: ValueCodec>(buf: &[u8]) -> String {
+    format!("{:?}", V::decode(buf))
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
 pub struct Key {
     asset_id: AssetId,
     date: Date,
@@ -25,7 +143,7 @@ pub struct Key {
 }
 
 impl Key {
-    fn new(asset_id: AssetId, date: Date, timestamp: Timestamp) -> Key {
+    pub fn new(asset_id: AssetId, date: Date, timestamp: Timestamp) -> Key {
         Key {
             asset_id,
             date,
@@ -34,29 +152,217 @@ impl Key {
     }
 }
 
+/// The window of knowledge time, inclusive at both ends, during which a row was the current version of its key.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct EffectiveRange {
+    pub start: EffTimestamp,
+    pub end: EffTimestamp,
+}
+
+impl EffectiveRange {
+    pub fn new(start: EffTimestamp, end: EffTimestamp) -> EffectiveRange {
+        EffectiveRange { start, end }
+    }
+
+    fn contains(&self, eff_timestamp: EffTimestamp) -> bool {
+        self.start <= eff_timestamp && eff_timestamp <= self.end
+    }
+}
+
+/// Gives each version of a row the effective range from when it was published until just before the next version of
+/// the same key was, or indefinitely for the latest version. The rows have to be sorted by key and then by when they
+/// were published, as `BTree::write_bitemporal_from_iterator` expects them. A version published at the same time as
+/// the next one is superseded straight away, and gets an empty range.
+pub fn effective_ranges<I>(source: I) -> impl Iterator<Item = (Key, Value, EffectiveRange)>
+where
+    I: Iterator<Item = (Key, Value, EffTimestamp)>,
+{
+    let mut source = source.peekable();
+    std::iter::from_fn(move || {
+        let (key, value, published_at) = source.next()?;
+        let end = match source.peek() {
+            Some((next_key, _, next_published_at)) if *next_key == key => {
+                next_published_at.saturating_sub(1)
+            }
+            _ => EffTimestamp::MAX,
+        };
+        Some((key, value, EffectiveRange::new(published_at, end)))
+    })
+}
+
+/// Whether a query sees the rows recorded exactly at its timestamp.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum TimestampBound {
+    #[default]
+    Inclusive,
+    Exclusive,
+}
+
+/// Finds the latest value as of `timestamp` for each date from `end_date` back to `start_date`, inclusive, for an
+/// asset.
+///
+/// The results come back in descending key order: from the latest date to the earliest, and with a `timestamp_range`,
+/// from the latest timestamp to the earliest within each date. A query only ever returns its own asset's rows, even
+/// when other assets have rows on the same dates, so covering several assets takes a query for each, whose results
+/// can be chained in whatever order the assets are wanted. `BTree::query_points` looks up several assets and dates
+/// at once, and returns them in the order they're asked for.
+///
+/// ```
+/// use findb::btree::file::{AssetId, Date, Query, Timestamp};
+///
+/// let query = Query::new(0, AssetId(1), Date(20200101), Date(20200131), Timestamp(0));
+/// ```
+///
+/// Each of the fields has its own type, so mixing them up doesn't compile:
+///
+/// ```compile_fail
+/// use findb::btree::file::{AssetId, Date, Query, Timestamp};
+///
+/// let query = Query::new(0, Date(20200101), AssetId(1), Date(20200131), Timestamp(0));
+/// ```
 pub struct Query {
     pub id: usize,
     pub asset_id: AssetId,
     pub start_date: Date,
     pub end_date: Date,
     pub timestamp: Timestamp,
+    /// Whether rows recorded at `timestamp` itself are returned, which they are by default.
+    pub timestamp_bound: TimestampBound,
+    /// If set, every row recorded within this window, inclusive at both ends, is returned for each date, in place of
+    /// the latest row as of `timestamp`.
+    pub timestamp_range: Option<(Timestamp, Timestamp)>,
+    /// If set, the row with the latest timestamp on record is returned for each date, whatever its timestamp, in
+    /// place of the latest row as of `timestamp`. It overrides `timestamp`, `timestamp_bound` and `timestamp_range`.
+    pub latest_known: bool,
+    /// If set, only rows whose effective range contains this timestamp are returned. Rows in files written without
+    /// effective ranges are always effective.
+    pub eff_timestamp: Option<EffTimestamp>,
+    /// If set, results are returned for at most this many dates, the latest ones in the range, and the iterator stops
+    /// there rather than reading back to `start_date`.
+    pub max_periods: Option<u32>,
+}
+
+impl Query {
+    pub fn new(
+        id: usize,
+        asset_id: AssetId,
+        start_date: Date,
+        end_date: Date,
+        timestamp: Timestamp,
+    ) -> Query {
+        Query {
+            id,
+            asset_id,
+            start_date,
+            end_date,
+            timestamp,
+            timestamp_bound: TimestampBound::Inclusive,
+            timestamp_range: None,
+            latest_known: false,
+            eff_timestamp: None,
+            max_periods: None,
+        }
+    }
+
+    /// The latest timestamp a row can have and still be returned by the query.
+    fn latest_timestamp(&self) -> Timestamp {
+        match self.timestamp_range {
+            _ if self.latest_known => Timestamp(u64::MAX),
+            Some((_, end)) => end,
+            None => self.timestamp,
+        }
+    }
+
+    /// Whether only the latest row the query sees is returned for each date.
+    fn one_row_per_date(&self) -> bool {
+        self.latest_known || self.timestamp_range.is_none()
+    }
+
+    /// Whether a row with the given timestamp was recorded outside the times the query sees.
+    fn excludes_timestamp(&self, timestamp: Timestamp) -> bool {
+        match (self.timestamp_range, self.timestamp_bound) {
+            _ if self.latest_known => false,
+            (Some((start, end)), _) => timestamp < start || timestamp > end,
+            (None, TimestampBound::Inclusive) => timestamp > self.timestamp,
+            (None, TimestampBound::Exclusive) => timestamp >= self.timestamp,
+        }
+    }
 }
 
 #[derive(PartialEq, PartialOrd, Debug)]
-pub struct QueryResult {
+pub struct QueryResult<V = Value> {
     id: usize,
     key: Key,
-    value: Value,
+    value: V,
+}
+
+impl<V: ValueCodec> QueryResult<V> {
+    /// The id of the query the result is for.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// The key of the row the result was read from.
+    pub fn key(&self) -> Key {
+        self.key
+    }
+
+    /// The row's value.
+    pub fn value(&self) -> V {
+        self.value
+    }
 }
 
-#[derive(Debug)]
+/// The version of the file format written by this version of the crate. Version 1 files start straight away with
+/// the page size, page count and root page number, and hold f32 values in plain leaves. Version 2 files start with
+/// `MAGIC` and the format version, followed by those fields and then the leaf type and value codec fields. Version 3 widens the timestamps in keys from 32 bits to 64. Version 4
+/// adds a field of flags to the end of the header.
+pub const FORMAT_VERSION: u32 = 4;
+const MAGIC: u32 = u32::from_be_bytes(*b"FNDB");
+
+#[derive(PartialEq, Debug)]
 struct FileHeader {
+    format_version: u32,
     page_size: u32,
     page_count: u32,
     root_page_num: PageNumber,
+    leaf_type: u32,
+    value_codec: u32,
+    value_width: u32,
+    flags: u32,
+}
+
+/// Set in a file whose pages are each compressed with zstd. The compressed pages are of different lengths, so they're
+/// followed at the end of the file by where each one starts and where the last one ends, as u64s.
+const COMPRESSED_FLAG: u32 = 0;
+
+impl FileHeader {
+    /// The number of bytes before the first page, which depends on the file's format version.
+    fn size(&self) -> usize {
+        match self.format_version {
+            1 => V1_FILE_HEADER_SIZE,
+            2 | 3 => V3_FILE_HEADER_SIZE,
+            _ => FILE_HEADER_SIZE,
+        }
+    }
+
+    fn is_compressed(&self) -> bool {
+        self.flags & (1 << COMPRESSED_FLAG) != 0
+    }
+
+    /// The width of the timestamps in the file's keys, which depends on the file's format version.
+    fn timestamp_width(&self) -> usize {
+        match self.format_version {
+            1 | 2 => U32_SIZE,
+            _ => U64_SIZE,
+        }
+    }
 }
 
-const FILE_HEADER_SIZE: usize = size_of::<FileHeader>();
+/// The header is preceded by the magic number.
+const FILE_HEADER_SIZE: usize = U32_SIZE + size_of::<FileHeader>();
+const V1_FILE_HEADER_SIZE: usize = 3 * U32_SIZE;
+const V3_FILE_HEADER_SIZE: usize = FILE_HEADER_SIZE - U32_SIZE;
 
 struct FileHeaderBuffer {
     buf: [u8; FILE_HEADER_SIZE],
@@ -69,123 +375,506 @@ impl FileHeaderBuffer {
         }
     }
 
-    fn from_file(file: &mut File) -> std::io::Result<FileHeaderBuffer> {
+    fn from_file<R: Read>(file: &mut R) -> std::io::Result<FileHeaderBuffer> {
         let mut buf = [0; FILE_HEADER_SIZE];
-        file.read(&mut buf).map(|_| FileHeaderBuffer { buf })
+        file.read_exact(&mut buf).map(|_| FileHeaderBuffer { buf })
+    }
+
+    /// Writes the header over the start of the file, then seeks back to where the file was positioned before.
+    fn write_to<W: Write + Seek>(&self, file: &mut W) -> std::io::Result<()> {
+        let position = file.stream_position()?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&self.buf)?;
+        file.seek(SeekFrom::Start(position))?;
+        Ok(())
     }
 
     fn set(&mut self, header: FileHeader) {
-        write_u32(&mut self.buf[0..], header.page_size);
-        write_u32(&mut self.buf[U32_SIZE..], header.page_count);
-        write_u32(&mut self.buf[2 * U32_SIZE..], header.root_page_num);
+        write_u32(&mut self.buf[0..], MAGIC);
+        write_u32(&mut self.buf[U32_SIZE..], header.format_version);
+        write_u32(&mut self.buf[2 * U32_SIZE..], header.page_size);
+        write_u32(&mut self.buf[3 * U32_SIZE..], header.page_count);
+        write_u32(&mut self.buf[4 * U32_SIZE..], header.root_page_num);
+        write_u32(&mut self.buf[5 * U32_SIZE..], header.leaf_type);
+        write_u32(&mut self.buf[6 * U32_SIZE..], header.value_codec);
+        write_u32(&mut self.buf[7 * U32_SIZE..], header.value_width);
+        write_u32(&mut self.buf[8 * U32_SIZE..], header.flags);
     }
 
-    fn get(&self) -> FileHeader {
-        FileHeader {
-            page_size: read_u32(&self.buf[0..]),
-            page_count: read_u32(&self.buf[U32_SIZE..]),
-            root_page_num: read_u32(&self.buf[2 * U32_SIZE..]),
+    /// Reads the header of a file in any supported format version. A file that doesn't start with the magic number
+    /// is taken to be version 1, which only has the fields up to `root_page_num`.
+    fn get(&self) -> std::io::Result<FileHeader> {
+        let (format_version, fields) = if read_u32(&self.buf[0..]) == MAGIC {
+            (read_u32(&self.buf[U32_SIZE..]), &self.buf[2 * U32_SIZE..])
+        } else {
+            (1, &self.buf[0..])
+        };
+        if format_version == 0 || format_version > FORMAT_VERSION {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Unsupported format version {}, expected at most {}",
+                    format_version, FORMAT_VERSION
+                ),
+            ));
+        }
+
+        let page_size = read_u32(&fields[0..]);
+        let page_count = read_u32(&fields[U32_SIZE..]);
+        let root_page_num = read_u32(&fields[2 * U32_SIZE..]);
+        if format_version == 1 {
+            return Ok(FileHeader {
+                format_version,
+                page_size,
+                page_count,
+                root_page_num,
+                leaf_type: LEAF_TYPE,
+                value_codec: Value::ID,
+                value_width: Value::WIDTH as u32,
+                flags: 0,
+            });
         }
+
+        // The width of the crate's own codecs is known, so a file recording another width for one is corrupt.
+        let value_codec = read_u32(&fields[4 * U32_SIZE..]);
+        let value_width = read_u32(&fields[5 * U32_SIZE..]);
+        let codec_width = match value_codec {
+            <f32 as ValueCodec>::ID => Some(f32::WIDTH),
+            <f64 as ValueCodec>::ID => Some(f64::WIDTH),
+            <i32 as ValueCodec>::ID => Some(i32::WIDTH),
+            DictionaryCode::ID => Some(DictionaryCode::WIDTH),
+            _ => None,
+        };
+        if let Some(width) = codec_width.filter(|width| *width != value_width as usize) {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Values with codec {} are {} bytes wide, not {}",
+                    value_codec, width, value_width
+                ),
+            ));
+        }
+
+        Ok(FileHeader {
+            format_version,
+            page_size,
+            page_count,
+            root_page_num,
+            leaf_type: read_u32(&fields[3 * U32_SIZE..]),
+            value_codec,
+            value_width,
+            flags: match format_version {
+                2 | 3 => 0,
+                _ => read_u32(&fields[6 * U32_SIZE..]),
+            },
+        })
     }
 }
 
+/// Creates a cache over the pages of a compressed file, reading where each page starts from the end of the file.
+#[cfg(feature = "compressed")]
+fn compressed_page_cache<R: Read + Seek + 'static>(
+    mut file: R,
+    header: &FileHeader,
+    page_cache_size: usize,
+) -> std::io::Result<PageCache> {
+    let page_count = header.page_count as usize;
+    let mut buf = vec![0; (page_count + 1) * U64_SIZE];
+    file.seek(SeekFrom::End(-(buf.len() as i64)))?;
+    file.read_exact(&mut buf)?;
+    let page_offsets = buf.chunks(U64_SIZE).map(read_u64).collect();
+    let page_size = header.page_size as usize;
+    let header_bytes = header.size() as u64;
+    Ok(
+        PageCache::new(file, page_size, page_cache_size, header_bytes)
+            .with_page_offsets(page_offsets),
+    )
+}
+
+#[cfg(not(feature = "compressed"))]
+fn compressed_page_cache<R: Read + Seek + 'static>(
+    _file: R,
+    _header: &FileHeader,
+    _page_cache_size: usize,
+) -> std::io::Result<PageCache> {
+    Err(std::io::Error::new(
+        ErrorKind::Unsupported,
+        "The file's pages are compressed, which needs the compressed feature to read",
+    ))
+}
+
+/// Replaces the header of an existing file without touching its pages.
+fn update_header<W: Write + Seek>(file: &mut W, header: FileHeader) -> std::io::Result<()> {
+    let mut file_header_buf = FileHeaderBuffer::new();
+    file_header_buf.set(header);
+    file_header_buf.write_to(file)
+}
+
 const LEAF_TYPE: u32 = 0;
 const INNER_TYPE: u32 = 1;
-const PAGE_HEADER_SIZE: usize = 4 * U32_SIZE;
-const KEY_VALUE_SIZE: usize = size_of::<Key>() + size_of::<Value>();
+/// A leaf whose values are each followed by the start and end of their effective range.
+const BITEMPORAL_LEAF_TYPE: u32 = 2;
+
+/// The fields at the start of every page, each stored as a u32 at the position given by its `*_FIELD` index.
+#[derive(PartialEq, Debug)]
+struct PageHeader {
+    page_type: u32,
+    num_keys: u32,
+    /// The previous leaf for a leaf, or the last child pointer for an inner page.
+    extra_page_num: u32,
+    /// A bitfield of per-page flags, all clear unless a feature sets them.
+    flags: u32,
+}
 
-fn page_size_for_keys(num_keys: u32) -> usize {
+const PAGE_HEADER_SIZE: usize = size_of::<PageHeader>();
+const PAGE_TYPE_FIELD: usize = 0;
+const NUM_KEYS_FIELD: usize = 1;
+const EXTRA_PAGE_NUM_FIELD: usize = 2;
+const FLAGS_FIELD: usize = 3;
+
+/// The page flag set on a leaf whose values are delta encoded. Such a leaf stores the smallest of its values and the
+/// size of a step after its header, and each value as the number of steps it is above the smallest.
+const DELTA_ENCODED_FLAG: u32 = 0;
+const DELTA_HEADER_SIZE: usize = 2 * size_of::<f32>();
+const DELTA_SIZE: usize = size_of::<u16>();
+/// The page flag set on a leaf whose keys are packed. Such a leaf stores its first key in full after its header and
+/// any delta header, and each key as how far its asset id, date and timestamp are from that key's, in a u16, an i16
+/// and an i32.
+const PACKED_KEYS_FLAG: u32 = 1;
+const PACKED_KEY_SIZE: usize = size_of::<u16>() + size_of::<i16>() + size_of::<i32>();
+const KEY_SIZE: usize = size_of::<Key>();
+/// The size of an entry in an inner page, or in a leaf of `Value`s.
+const KEY_VALUE_SIZE: usize = KEY_SIZE + size_of::<Value>();
+/// The size of an entry in an inner page, a key and the page before it, the last page going in the page header.
+const INNER_ENTRY_SIZE: usize = KEY_SIZE + size_of::<PageNumber>();
+const EFFECTIVE_RANGE_SIZE: usize = 2 * U64_SIZE;
+const BITEMPORAL_KEY_VALUE_SIZE: usize = KEY_VALUE_SIZE + EFFECTIVE_RANGE_SIZE;
+
+pub fn page_size_for_keys(num_keys: u32) -> usize {
     PAGE_HEADER_SIZE + (num_keys as usize) * KEY_VALUE_SIZE
 }
 
+/// The page size that fits the given number of keys in a leaf of values encoded with `V`.
+pub fn page_size_for_keys_of<V: ValueCodec>(num_keys: u32) -> usize {
+    PAGE_HEADER_SIZE + (num_keys as usize) * (KEY_SIZE + V::WIDTH)
+}
+
+pub fn bitemporal_page_size_for_keys(num_keys: u32) -> usize {
+    PAGE_HEADER_SIZE + (num_keys as usize) * BITEMPORAL_KEY_VALUE_SIZE
+}
+
+/// The smallest page that fits a leaf entry of values encoded with `V`, or an inner key along with its two children.
+fn min_page_size<V: ValueCodec>() -> usize {
+    page_size_for_keys_of::<V>(1).max(PAGE_HEADER_SIZE + INNER_ENTRY_SIZE)
+}
+
+/// The size of the blocks the OS reads a file in, which page sizes are best kept a multiple of.
+pub const BLOCK_SIZE: u32 = 4096;
+
+/// Checks a page size before a tree is written with it. Pages have to hold at least `min_page_size` bytes, which
+/// `page_size_for_keys` and its variants give for a number of keys. A page size that isn't a multiple of `block_size`
+/// leaves pages straddling blocks, which is logged as a warning when logging is enabled, or rejected if `strict`.
+pub fn check_page_size(
+    page_size: u32,
+    min_page_size: usize,
+    block_size: u32,
+    strict: bool,
+) -> std::io::Result<()> {
+    if (page_size as usize) < min_page_size {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Page size {} is smaller than the minimum of {}",
+                page_size, min_page_size
+            ),
+        ));
+    }
+    let aligned = page_size.is_multiple_of(block_size);
+    if !aligned && strict {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Page size {} isn't a multiple of the block size {}",
+                page_size, block_size
+            ),
+        ));
+    }
+    #[cfg(feature = "log")]
+    if !aligned {
+        log::warn!(
+            "Page size {} isn't a multiple of the block size {}",
+            page_size,
+            block_size
+        );
+    }
+    Ok(())
+}
+
+/// How far a key's asset id, date and timestamp are from those of the first key of a leaf with packed keys, or None
+/// if they're too far to be packed, in which case the key has to start a new leaf.
+fn packed_key(base: &Key, key: &Key) -> Option<(u16, i16, i32)> {
+    let asset_offset = key
+        .asset_id
+        .0
+        .checked_sub(base.asset_id.0)?
+        .try_into()
+        .ok()?;
+    let date_offset = (key.date.0 as i64 - base.date.0 as i64).try_into().ok()?;
+    let timestamp_offset = (key.timestamp.0 as i128 - base.timestamp.0 as i128)
+        .try_into()
+        .ok()?;
+    Some((asset_offset, date_offset, timestamp_offset))
+}
+
 trait Page {
     fn buf(&self) -> &[u8];
 
+    /// The width of the values in a leaf, which comes from the file header.
+    fn value_width(&self) -> usize;
+
+    /// The width of the timestamps in keys, which comes from the file's format version.
+    fn timestamp_width(&self) -> usize;
+
+    fn key_size(&self) -> usize {
+        2 * U32_SIZE + self.timestamp_width()
+    }
+
     fn header_field(&self, index: usize) -> u32 {
         read_u32(&self.buf()[index * U32_SIZE..])
     }
 
+    fn header(&self) -> PageHeader {
+        PageHeader {
+            page_type: self.page_type(),
+            num_keys: self.num_keys(),
+            extra_page_num: self.extra_page_num(),
+            flags: self.header_field(FLAGS_FIELD),
+        }
+    }
+
     fn page_type(&self) -> u32 {
-        self.header_field(0)
+        self.header_field(PAGE_TYPE_FIELD)
     }
 
     fn num_keys(&self) -> u32 {
-        self.header_field(1)
+        self.header_field(NUM_KEYS_FIELD)
     }
 
     fn extra_page_num(&self) -> u32 {
-        self.header_field(2)
+        self.header_field(EXTRA_PAGE_NUM_FIELD)
+    }
+
+    /// Whether the given bit, counting from 0, is set in the page's flags.
+    fn flag(&self, bit: u32) -> bool {
+        self.header_field(FLAGS_FIELD) & (1 << bit) != 0
+    }
+
+    fn is_leaf(&self) -> bool {
+        self.page_type() != INNER_TYPE
+    }
+
+    fn is_delta_encoded(&self) -> bool {
+        self.flag(DELTA_ENCODED_FLAG)
+    }
+
+    fn has_packed_keys(&self) -> bool {
+        self.flag(PACKED_KEYS_FLAG)
+    }
+
+    /// The number of bytes each key takes up in the page's entries.
+    fn entry_key_size(&self) -> usize {
+        if self.has_packed_keys() {
+            PACKED_KEY_SIZE
+        } else {
+            self.key_size()
+        }
+    }
+
+    fn entry_size(&self) -> usize {
+        match self.page_type() {
+            INNER_TYPE => self.key_size() + size_of::<PageNumber>(),
+            _ if self.is_delta_encoded() => self.entry_key_size() + DELTA_SIZE,
+            BITEMPORAL_LEAF_TYPE => {
+                self.entry_key_size() + self.value_width() + EFFECTIVE_RANGE_SIZE
+            }
+            _ => self.entry_key_size() + self.value_width(),
+        }
+    }
+
+    /// The offset of the first key of a leaf with packed keys, which follows the page header and any delta header.
+    fn packed_base_offset(&self) -> usize {
+        if self.is_delta_encoded() {
+            PAGE_HEADER_SIZE + DELTA_HEADER_SIZE
+        } else {
+            PAGE_HEADER_SIZE
+        }
+    }
+
+    /// The offset of the first entry, which follows the page header and, in a delta encoded leaf, the delta header,
+    /// as well as the first key in full in a leaf with packed keys.
+    fn entries_offset(&self) -> usize {
+        if self.has_packed_keys() {
+            self.packed_base_offset() + KEY_SIZE
+        } else {
+            self.packed_base_offset()
+        }
     }
 
     fn key_capacity(&self) -> usize {
-        (self.buf().len() - PAGE_HEADER_SIZE) / KEY_VALUE_SIZE
+        (self.buf().len() - self.entries_offset()) / self.entry_size()
     }
 
     fn key_offset(&self, index: usize) -> usize {
-        PAGE_HEADER_SIZE + KEY_VALUE_SIZE * index
+        self.entries_offset() + self.entry_size() * index
     }
 
     fn key(&self, index: usize) -> Key {
+        if self.has_packed_keys() {
+            let base = self.packed_base();
+            let offset = self.key_offset(index);
+            let buf = &self.buf()[offset..];
+            let asset_offset = u16::from_be_bytes([buf[0], buf[1]]);
+            let date_offset = i16::from_be_bytes([buf[2], buf[3]]);
+            let timestamp_offset = i32::from_be_bytes(buf[4..8].try_into().unwrap());
+            return Key {
+                asset_id: AssetId(base.asset_id.0 + asset_offset as u32),
+                date: Date(base.date.0.wrapping_add(date_offset as u32)),
+                timestamp: Timestamp(base.timestamp.0.wrapping_add(timestamp_offset as u64)),
+            };
+        }
         let offset = self.key_offset(index);
+        let timestamp_buf = &self.buf()[offset + 2 * U32_SIZE..];
+        let timestamp = match self.timestamp_width() {
+            U32_SIZE => read_u32(timestamp_buf) as u64,
+            _ => read_u64(timestamp_buf),
+        };
+        Key {
+            asset_id: AssetId(read_u32(&self.buf()[offset..])),
+            date: Date(read_u32(&self.buf()[offset + U32_SIZE..])),
+            timestamp: Timestamp(timestamp),
+        }
+    }
+
+    /// The first key of a leaf with packed keys, which the other keys are stored relative to.
+    fn packed_base(&self) -> Key {
+        let offset = self.packed_base_offset();
         Key {
-            asset_id: read_u32(&self.buf()[offset..]),
-            date: read_u32(&self.buf()[offset + U32_SIZE..]),
-            timestamp: read_u32(&self.buf()[offset + 2 * U32_SIZE..]),
+            asset_id: AssetId(read_u32(&self.buf()[offset..])),
+            date: Date(read_u32(&self.buf()[offset + U32_SIZE..])),
+            timestamp: Timestamp(read_u64(&self.buf()[offset + 2 * U32_SIZE..])),
         }
     }
 
     fn value_offset(&self, index: usize) -> usize {
-        self.key_offset(index) + size_of::<Key>()
+        self.key_offset(index) + self.entry_key_size()
+    }
+
+    fn value<V: ValueCodec>(&self, index: usize) -> V {
+        debug_assert_eq!(V::WIDTH, self.value_width());
+        if self.is_delta_encoded() {
+            // Only f32 values are delta encoded.
+            debug_assert_eq!(V::ID, f32::ID);
+            let mut buf = [0; size_of::<f32>()];
+            self.delta_value(index).encode(&mut buf);
+            V::decode(&buf)
+        } else {
+            V::decode(&self.buf()[self.value_offset(index)..])
+        }
+    }
+
+    fn delta_value(&self, index: usize) -> f32 {
+        let min = read_f32(&self.buf()[PAGE_HEADER_SIZE..]);
+        let step = read_f32(&self.buf()[PAGE_HEADER_SIZE + size_of::<f32>()..]);
+        let offset = self.value_offset(index);
+        let steps = u16::from_be_bytes([self.buf()[offset], self.buf()[offset + 1]]);
+        min + steps as f32 * step
     }
 
-    fn value(&self, index: usize) -> Value {
-        read_f32(&self.buf()[self.value_offset(index)..])
+    fn effective_range(&self, index: usize) -> Option<EffectiveRange> {
+        if self.page_type() == BITEMPORAL_LEAF_TYPE {
+            let offset = self.value_offset(index) + self.value_width();
+            Some(EffectiveRange {
+                start: read_u64(&self.buf()[offset..]),
+                end: read_u64(&self.buf()[offset + U64_SIZE..]),
+            })
+        } else {
+            None
+        }
     }
 
     fn page_number(&self, index: usize) -> PageNumber {
         read_u32(&self.buf()[self.value_offset(index)..])
     }
 
-    fn index_of(&self, key: &Key) -> u32 {
-        let mut min = 0;
-        let mut max = self.num_keys();
-
-        while min < max {
-            let midpoint = (max + min) / 2;
-            let midpoint_key = self.key(midpoint as usize);
-            match (*key).partial_cmp(&midpoint_key).unwrap() {
-                Ordering::Greater => min = midpoint + 1,
-                Ordering::Less => max = midpoint,
-                Ordering::Equal => {
-                    if self.page_type() == LEAF_TYPE {
-                        min = midpoint;
-                    } else {
-                        min = midpoint + 1;
-                    }
-                    break;
+    /// Returns the page numbers of an inner page's children, the last of which is kept in the extra page number when
+    /// the page is full.
+    fn children(&self) -> Vec<PageNumber> {
+        (0..=self.num_keys() as usize)
+            .map(|index| {
+                if index < self.key_capacity() {
+                    self.page_number(index)
+                } else {
+                    self.extra_page_num()
                 }
+            })
+            .collect()
+    }
+
+    /// Returns the index of the first key in the page that's greater than the given key. Repeated keys, such as the
+    /// corrected versions of a row, are stored in the order they were written, so this is the position a scan back
+    /// through the page has to start from to see the latest of them first.
+    fn index_of(&self, key: &Key) -> u32 {
+        node::partition_point(self.num_keys() as usize, |index| self.key(index) <= *key) as u32
+    }
+
+    /// Checks that the keys in the page are in ascending order, returning the index of the first key that's less than
+    /// its predecessor. Equal neighbouring keys are allowed, since the writer stores repeated keys from its source
+    /// as they are.
+    fn check_key_order(&self) -> Result<(), usize> {
+        let mut previous_key = None;
+        for index in 0..(self.num_keys() as usize) {
+            let key = self.key(index);
+            if previous_key.is_some_and(|previous_key| key < previous_key) {
+                return Err(index);
             }
+            previous_key = Some(key);
         }
-        min
+        Ok(())
     }
 
-    fn print(&self) {
-        let page_type = self.page_type();
-        println!("Page Type: {}", page_type);
-        println!("Num Keys: {}", self.num_keys());
-        println!("Rightmost Page Num: {}", self.extra_page_num());
-        let max_keys = if page_type == LEAF_TYPE {
+    fn print(&self, format_value: fn(&[u8]) -> String) {
+        let header = self.header();
+        println!("Page Type: {}", header.page_type);
+        println!("Num Keys: {}", header.num_keys);
+        println!("Rightmost Page Num: {}", header.extra_page_num);
+        println!("Flags: {:#b}", header.flags);
+        let max_keys = if self.is_leaf() {
             self.num_keys()
         } else {
             min(self.num_keys() + 1, self.key_capacity() as u32)
         };
         for i in 0..max_keys {
-            if page_type == LEAF_TYPE {
+            if let Some(effective_range) = self.effective_range(i as usize) {
+                println!(
+                    "Index {}: ({:?}, {}, {:?})",
+                    i,
+                    self.key(i as usize),
+                    format_value(&self.buf()[self.value_offset(i as usize)..]),
+                    effective_range
+                );
+            } else if self.is_delta_encoded() {
+                println!(
+                    "Index {}: ({:?}, {:?})",
+                    i,
+                    self.key(i as usize),
+                    self.delta_value(i as usize)
+                );
+            } else if self.is_leaf() {
                 println!(
                     "Index {}: ({:?}, {})",
                     i,
                     self.key(i as usize),
-                    self.value(i as usize)
+                    format_value(&self.buf()[self.value_offset(i as usize)..])
                 );
             } else {
                 println!(
@@ -207,23 +896,93 @@ trait MutPage: Page {
     }
 
     fn set_num_keys(&mut self, num_keys: u32) {
-        self.set_header_field(1, num_keys);
+        self.set_header_field(NUM_KEYS_FIELD, num_keys);
     }
 
     fn set_extra_page_num(&mut self, page_num: u32) {
-        self.set_header_field(2, page_num);
+        self.set_header_field(EXTRA_PAGE_NUM_FIELD, page_num);
+    }
+
+    fn set_flag(&mut self, bit: u32, value: bool) {
+        let flags = self.header_field(FLAGS_FIELD);
+        let flags = if value {
+            flags | (1 << bit)
+        } else {
+            flags & !(1 << bit)
+        };
+        self.set_header_field(FLAGS_FIELD, flags);
     }
 
     fn set_key(&mut self, index: usize, key: Key) {
+        if self.has_packed_keys() {
+            if index == 0 {
+                let offset = self.packed_base_offset();
+                write_u32(&mut self.mut_buf()[offset..], key.asset_id.0);
+                write_u32(&mut self.mut_buf()[offset + U32_SIZE..], key.date.0);
+                write_u64(
+                    &mut self.mut_buf()[offset + 2 * U32_SIZE..],
+                    key.timestamp.0,
+                );
+            }
+            let (asset_offset, date_offset, timestamp_offset) =
+                packed_key(&self.packed_base(), &key)
+                    .expect("The key is too far from the leaf's first key to be packed");
+            let offset = self.key_offset(index);
+            let buf = &mut self.mut_buf()[offset..];
+            buf[0..2].copy_from_slice(&asset_offset.to_be_bytes());
+            buf[2..4].copy_from_slice(&date_offset.to_be_bytes());
+            buf[4..8].copy_from_slice(&timestamp_offset.to_be_bytes());
+            return;
+        }
         let offset = self.key_offset(index);
-        write_u32(&mut self.mut_buf()[offset..], key.asset_id);
-        write_u32(&mut self.mut_buf()[offset + U32_SIZE..], key.date);
-        write_u32(&mut self.mut_buf()[offset + 2 * U32_SIZE..], key.timestamp);
+        write_u32(&mut self.mut_buf()[offset..], key.asset_id.0);
+        write_u32(&mut self.mut_buf()[offset + U32_SIZE..], key.date.0);
+        let timestamp_width = self.timestamp_width();
+        let timestamp_buf = &mut self.mut_buf()[offset + 2 * U32_SIZE..];
+        match timestamp_width {
+            U32_SIZE => write_u32(timestamp_buf, key.timestamp.0 as u32),
+            _ => write_u64(timestamp_buf, key.timestamp.0),
+        }
     }
 
-    fn set_value(&mut self, index: usize, value: Value) {
+    fn set_value<V: ValueCodec>(&mut self, index: usize, value: V) {
+        debug_assert_eq!(V::WIDTH, self.value_width());
         let offset = self.value_offset(index);
-        write_f32(&mut self.mut_buf()[offset..], value)
+        value.encode(&mut self.mut_buf()[offset..])
+    }
+
+    /// Sets the values of a delta encoded leaf, one for each of its keys. The values have to be finite.
+    fn set_delta_values(&mut self, values: &[f32]) {
+        let min = values.iter().copied().fold(f32::INFINITY, f32::min);
+        let max = values.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let step = if max > min {
+            (max - min) / u16::MAX as f32
+        } else {
+            0.0
+        };
+        write_f32(&mut self.mut_buf()[PAGE_HEADER_SIZE..], min);
+        write_f32(
+            &mut self.mut_buf()[PAGE_HEADER_SIZE + size_of::<f32>()..],
+            step,
+        );
+        for (index, value) in values.iter().enumerate() {
+            let steps = if step > 0.0 {
+                ((value - min) / step).round() as u16
+            } else {
+                0
+            };
+            let offset = self.value_offset(index);
+            self.mut_buf()[offset..(offset + DELTA_SIZE)].copy_from_slice(&steps.to_be_bytes());
+        }
+    }
+
+    fn set_effective_range(&mut self, index: usize, effective_range: EffectiveRange) {
+        let offset = self.value_offset(index) + self.value_width();
+        write_u64(&mut self.mut_buf()[offset..], effective_range.start);
+        write_u64(
+            &mut self.mut_buf()[offset + U64_SIZE..],
+            effective_range.end,
+        );
     }
 
     fn set_page_number(&mut self, index: usize, page_number: PageNumber) {
@@ -234,23 +993,29 @@ trait MutPage: Page {
 
 struct PageBuffer {
     buf: Vec<u8>,
+    value_width: usize,
+    timestamp_width: usize,
 }
 
 impl PageBuffer {
-    fn new(page_size: u32, page_type: u32) -> PageBuffer {
-        let mut buf = Vec::with_capacity(page_size as usize);
-        for _ in 0..page_size {
-            buf.push(0);
-        }
-        let mut buf = PageBuffer { buf };
-        buf.set_header_field(0, page_type);
+    /// Creates a page in the current format.
+    fn new(page_size: u32, page_type: u32, value_width: usize) -> PageBuffer {
+        let mut buf = PageBuffer {
+            buf: vec![0; page_size as usize],
+            value_width,
+            timestamp_width: U64_SIZE,
+        };
+        buf.set_header_field(PAGE_TYPE_FIELD, page_type);
         buf
     }
 
+    /// Zeroes the page, apart from its type and flags.
     fn clear(&mut self) {
-        for i in 0..self.buf.capacity() {
-            self.buf[i] = 0;
-        }
+        let page_type = self.page_type();
+        let flags = self.header_field(FLAGS_FIELD);
+        self.buf.iter_mut().for_each(|b| *b = 0);
+        self.set_header_field(PAGE_TYPE_FIELD, page_type);
+        self.set_header_field(FLAGS_FIELD, flags);
     }
 }
 
@@ -258,6 +1023,14 @@ impl Page for PageBuffer {
     fn buf(&self) -> &[u8] {
         &self.buf[..]
     }
+
+    fn value_width(&self) -> usize {
+        self.value_width
+    }
+
+    fn timestamp_width(&self) -> usize {
+        self.timestamp_width
+    }
 }
 
 impl MutPage for PageBuffer {
@@ -266,12 +1039,79 @@ impl MutPage for PageBuffer {
     }
 }
 
-impl Page for &[u8] {
+/// A page loaded into the page cache.
+struct CachedPage<'a> {
+    buf: &'a [u8],
+    value_width: usize,
+    timestamp_width: usize,
+}
+
+impl Page for CachedPage<'_> {
     fn buf(&self) -> &[u8] {
-        self
+        self.buf
+    }
+
+    fn value_width(&self) -> usize {
+        self.value_width
+    }
+
+    fn timestamp_width(&self) -> usize {
+        self.timestamp_width
+    }
+}
+
+/// A leaf page read as holding values encoded with `V`, along with their effective ranges in a bitemporal file. Its
+/// sibling is the previous leaf, since queries scan back from their latest date.
+struct LeafPage<'a, V> {
+    page: &'a PageBuffer,
+    values: PhantomData<V>,
+}
+
+impl<'a, V: ValueCodec> LeafPage<'a, V> {
+    fn new(page: &'a PageBuffer) -> LeafPage<'a, V> {
+        LeafPage {
+            page,
+            values: PhantomData,
+        }
+    }
+}
+
+impl<V: ValueCodec> LeafNode for LeafPage<'_, V> {
+    type Key = Key;
+    type Value = V;
+    type Sibling = PageNumber;
+
+    fn num_keys(&self) -> usize {
+        self.page.num_keys() as usize
+    }
+
+    fn key_at(&self, index: usize) -> Key {
+        self.page.key(index)
+    }
+
+    fn value_at(&self, index: usize) -> V {
+        self.page.value(index)
+    }
+
+    fn sibling(&self) -> Option<PageNumber> {
+        Some(self.page.extra_page_num()).filter(|&page_num| page_num != u32::MAX)
     }
 }
 
+/// How full the pages of a file are, to help choose a page size or decide whether a file is worth rewriting.
+#[derive(PartialEq, Debug)]
+pub struct FillStats {
+    pub leaves: usize,
+    /// The mean, least and greatest share of a leaf's key capacity that it uses, from 0 to 1.
+    pub mean_occupancy: f64,
+    pub min_occupancy: f64,
+    pub max_occupancy: f64,
+    /// The bytes of the leaves taken up by neither their headers nor their entries.
+    pub slack_bytes: u64,
+    /// The number of inner pages with each number of children.
+    pub inner_fanout: BTreeMap<usize, usize>,
+}
+
 pub struct BTree {
     file_header: FileHeader,
     page_cache: PageCache,
@@ -279,11 +1119,26 @@ pub struct BTree {
 
 impl BTree {
     pub fn from_file(file: File, page_cache_size: usize) -> std::io::Result<BTree> {
-        let mut file = file;
+        BTree::from_reader(file, page_cache_size)
+    }
+
+    /// Opens a tree from anything it can be read from, such as the bytes `write_to_vec` returns wrapped in a
+    /// `std::io::Cursor`. The tree is read from the start, wherever the reader is positioned.
+    pub fn from_reader<R: Read + Seek + 'static>(
+        reader: R,
+        page_cache_size: usize,
+    ) -> std::io::Result<BTree> {
+        let mut file = reader;
+        file.seek(SeekFrom::Start(0))?;
         let file_header_buf = FileHeaderBuffer::from_file(&mut file)?;
-        let file_header = file_header_buf.get();
+        let file_header = file_header_buf.get()?;
         let page_size = file_header.page_size as usize;
-        let page_cache = PageCache::new(file, page_size, page_cache_size, FILE_HEADER_SIZE as u64);
+        let header_bytes = file_header.size() as u64;
+        let page_cache = if file_header.is_compressed() {
+            compressed_page_cache(file, &file_header, page_cache_size)?
+        } else {
+            PageCache::new(file, page_size, page_cache_size, header_bytes)
+        };
 
         Ok(BTree {
             file_header,
@@ -291,421 +1146,4134 @@ impl BTree {
         })
     }
 
-    /// Writes a new BTree file from an iterator that returns the keys and values to be loaded in their key sorted
-    /// order.
+    /// Opens a file read-only through a memory map. Pages are read straight from the mapping, with the OS keeping the
+    /// ones in use in memory, so unlike `from_file` there's no buffer of cached pages, which adds up when a process
+    /// has many small files open. A query still copies the leaf it's on. The caller has to make sure the file isn't
+    /// written to in place while it's open, since a change under the mapping can crash the process. The crate's own
+    /// writers, `migrate` included, only ever replace a file by renaming a new one over it, which leaves the mapped
+    /// file as it was.
+    #[cfg(feature = "mmap")]
+    pub fn open_mmap(file_name: &str) -> std::io::Result<BTree> {
+        let file = File::open(file_name)?;
+        // Safety: the mapping is only read, and the caller keeps the file from being changed while it's mapped.
+        let mmap = unsafe { memmap2::Mmap::map(&file)? };
+        let file_header = FileHeaderBuffer::from_file(&mut &mmap[..])?.get()?;
+        if file_header.is_compressed() {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "A file of compressed pages can't be mapped",
+            ));
+        }
+        let page_size = file_header.page_size as usize;
+        let header_bytes = file_header.size() as u64;
+        Ok(BTree {
+            file_header,
+            page_cache: PageCache::mapped(mmap, page_size, header_bytes),
+        })
+    }
+
+    /// Returns true if the file's rows carry effective ranges.
+    pub fn is_bitemporal(&self) -> bool {
+        self.file_header.leaf_type == BITEMPORAL_LEAF_TYPE
+    }
+
+    /// Changes the number of pages held in the page cache, keeping as many of the cached pages as fit.
+    pub fn resize_cache(&mut self, pages: usize) -> std::io::Result<()> {
+        self.page_cache.resize(pages)
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.page_cache.stats()
+    }
+
+    /// The memory the page cache has set aside for pages, which is none for a file opened with `open_mmap`.
+    pub fn cache_bytes(&self) -> usize {
+        self.page_cache.buffer_bytes()
+    }
+
+    /// Loads the pages on the path from the root to an asset's first leaf into the page cache, pinning the inner pages
+    /// there if `pin` is set, so that a query for the asset that follows doesn't have to read them from the file.
+    pub fn warm(&mut self, asset_id: AssetId, pin: bool) -> std::io::Result<()> {
+        let key = Key::new(asset_id, Date(0), Timestamp(0));
+        let mut page_num = self.file_header.root_page_num;
+        loop {
+            let page = self.load_page(page_num)?;
+            if page.is_leaf() {
+                return Ok(());
+            }
+            let index = page.index_of(&key) as usize;
+            let child_page_num = if index < page.key_capacity() {
+                page.page_number(index)
+            } else {
+                page.extra_page_num()
+            };
+            if pin {
+                self.page_cache.pin(page_num as usize)?;
+            }
+            page_num = child_page_num;
+        }
+    }
+
+    /// Rewrites a file of any supported format version into a new file in the current format. Pages with keys as wide
+    /// as the current format's are copied as they are. Files from before version 3 have narrower timestamps, so their
+    /// rows are read back and written into a new tree, which packs fewer of them into each page. Either way, the new
+    /// file only replaces any file at `dst_file_name` once it's complete.
+    pub fn migrate(src_file_name: &str, dst_file_name: &str) -> std::io::Result<()> {
+        let mut src = File::open(src_file_name)?;
+        let header = FileHeaderBuffer::from_file(&mut src)?.get()?;
+        if header.timestamp_width() != U64_SIZE {
+            let mut btree = BTree::from_file(src, 1)?;
+            return match header.value_codec {
+                <f64 as ValueCodec>::ID => btree.rewrite::<f64>(dst_file_name),
+                <i32 as ValueCodec>::ID => btree.rewrite::<i32>(dst_file_name),
+                DictionaryCode::ID => btree.rewrite::<DictionaryCode>(dst_file_name),
+                _ => btree.rewrite::<f32>(dst_file_name),
+            };
+        }
+        src.seek(SeekFrom::Start(header.size() as u64))?;
+
+        let (temp_file, mut dst) = TempFile::create(dst_file_name)?;
+        update_header(
+            &mut dst,
+            FileHeader {
+                format_version: FORMAT_VERSION,
+                ..header
+            },
+        )?;
+        dst.seek(SeekFrom::Start(FILE_HEADER_SIZE as u64))?;
+        std::io::copy(&mut src, &mut dst)?;
+        temp_file.persist(dst)
+    }
+
+    /// Writes a copy of a file in the current format with each of its pages compressed with zstd at the given level,
+    /// for archiving a file that won't be written to again. The copy can be opened and queried like any other file,
+    /// with its pages decompressed as they're read.
+    #[cfg(feature = "compressed")]
+    pub fn compress(src_file_name: &str, dst_file_name: &str, level: i32) -> std::io::Result<()> {
+        let mut src = File::open(src_file_name)?;
+        let header = FileHeaderBuffer::from_file(&mut src)?.get()?;
+        if header.format_version != FORMAT_VERSION || header.is_compressed() {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Only an uncompressed file in format version {} can be compressed",
+                    FORMAT_VERSION
+                ),
+            ));
+        }
+        src.seek(SeekFrom::Start(header.size() as u64))?;
+
+        let mut page = vec![0; header.page_size as usize];
+        let page_count = header.page_count as usize;
+        let (temp_file, mut dst) = TempFile::create(dst_file_name)?;
+        update_header(
+            &mut dst,
+            FileHeader {
+                flags: header.flags | (1 << COMPRESSED_FLAG),
+                ..header
+            },
+        )?;
+        dst.seek(SeekFrom::Start(FILE_HEADER_SIZE as u64))?;
+
+        let mut compressor = zstd::bulk::Compressor::new(level)?;
+        let mut page_offsets = vec![0; (page_count + 1) * U64_SIZE];
+        let mut offset = FILE_HEADER_SIZE as u64;
+        for page_num in 0..page_count {
+            src.read_exact(&mut page)?;
+            let compressed = compressor.compress(&page)?;
+            dst.write_all(&compressed)?;
+            write_u64(&mut page_offsets[page_num * U64_SIZE..], offset);
+            offset += compressed.len() as u64;
+        }
+        write_u64(&mut page_offsets[page_count * U64_SIZE..], offset);
+        dst.write_all(&page_offsets)?;
+        temp_file.persist(dst)
+    }
+
+    /// Writes the rows of the tree into a new file in the current format, a leaf at a time, keeping the leaf type and
+    /// whether the leaves are delta encoded.
+    fn rewrite<V: ValueCodec>(&mut self, dst_file_name: &str) -> std::io::Result<()> {
+        // The leaves link back to the ones before them, so they're listed from the last before being read in order.
+        let mut leaves = Vec::new();
+        let mut page_num = self.last_leaf()?;
+        while page_num != u32::MAX {
+            leaves.push(page_num);
+            page_num = self.load_page(page_num)?.extra_page_num();
+        }
+        let delta_encode = self.load_page(*leaves.last().unwrap())?.is_delta_encoded();
+        let page_size = self.file_header.page_size;
+        let leaf_type = self.file_header.leaf_type;
+
+        // A leaf that can't be read stops the write before the new file replaces anything.
+        let mut rows = leaves
+            .into_iter()
+            .rev()
+            .map(|page_num| {
+                let page = self.load_page(page_num)?;
+                Ok((0..page.num_keys() as usize)
+                    .map(|index| {
+                        Ok((
+                            page.key(index),
+                            page.value::<V>(index),
+                            page.effective_range(index),
+                        ))
+                    })
+                    .collect::<Vec<_>>())
+            })
+            .flat_map(|rows: std::io::Result<Vec<_>>| match rows {
+                Ok(rows) => rows,
+                Err(e) => vec![Err(e)],
+            });
+        BTree::write_leaves(
+            dst_file_name,
+            page_size,
+            leaf_type,
+            1.0,
+            delta_encode,
+            &mut rows,
+        )
+    }
+
+    /// Returns the size in bytes of the file `write_from_iterator` would write for the given number of keys. Leaves
+    /// are filled to capacity, as are the inner nodes above them, which each point to one more page than they have
+    /// keys. Fails if the page size is too small to write a tree with.
+    pub fn estimate_file_size(num_keys: usize, page_size: u32) -> std::io::Result<u64> {
+        BTree::estimate_values_file_size::<Value>(num_keys, page_size)
+    }
+
+    /// Returns the size in bytes of the file `write_values_from_iterator` would write for the given number of keys
+    /// with values encoded with `V`.
+    pub fn estimate_values_file_size<V: ValueCodec>(
+        num_keys: usize,
+        page_size: u32,
+    ) -> std::io::Result<u64> {
+        let min_page_size = min_page_size::<V>();
+        if (page_size as usize) < min_page_size {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Page size {} is smaller than the minimum of {}",
+                    page_size, min_page_size
+                ),
+            ));
+        }
+        let key_capacity = (page_size as usize - PAGE_HEADER_SIZE) / (KEY_SIZE + V::WIDTH);
+        let fanout = (page_size as usize - PAGE_HEADER_SIZE) / INNER_ENTRY_SIZE + 1;
+        let mut level_pages = num_keys.div_ceil(key_capacity).max(1);
+        let mut page_count = level_pages;
+        while level_pages > 1 {
+            level_pages = level_pages.div_ceil(fanout);
+            page_count += level_pages;
+        }
+        Ok(FILE_HEADER_SIZE as u64 + page_count as u64 * page_size as u64)
+    }
+
+    /// Returns the smallest fanout, the most children an inner page of `write_with_fanout` points to, that makes a tree
+    /// of the given number of keys the given number of levels tall, counting the leaves. Returns None if no fanout an
+    /// inner page of the given size can hold makes the tree that tall, or if the page size is too small to write a tree
+    /// with.
+    pub fn recommended_fanout(
+        num_keys: usize,
+        page_size: u32,
+        target_height: usize,
+    ) -> Option<usize> {
+        if (page_size as usize) < min_page_size::<Value>() {
+            return None;
+        }
+        let key_capacity = (page_size as usize - PAGE_HEADER_SIZE) / KEY_VALUE_SIZE;
+        let max_fanout = (page_size as usize - PAGE_HEADER_SIZE) / INNER_ENTRY_SIZE + 1;
+        let leaves = num_keys.div_ceil(key_capacity).max(1);
+        (2..=max_fanout).find(|&fanout| {
+            let mut level_pages = leaves;
+            let mut height = 1;
+            while level_pages > 1 {
+                level_pages = level_pages.div_ceil(fanout);
+                height += 1;
+            }
+            height == target_height
+        })
+    }
+
+    /// Writes a new BTree file from an iterator that returns the keys and values to be loaded in their key sorted
+    /// order. The tree is written to `<file_name>.tmp` and only replaces any existing file once it's complete.
     pub fn write_from_iterator(
         file_name: &str,
         page_size: u32,
         source: &mut dyn Iterator<Item = (Key, Value)>,
     ) -> std::io::Result<()> {
-        let mut file = File::create(file_name)?;
-        let mut file_header_buf = FileHeaderBuffer::new();
-        file_header_buf.set(FileHeader {
+        let (temp_file, file) = TempFile::create(file_name)?;
+        let file = BTree::write_to(file, page_size, source)?;
+        temp_file.persist(file)
+    }
+
+    /// Writes a tree from the start of `out`, from an iterator that returns the keys and values to be loaded in their
+    /// key sorted order, and returns `out` once the tree is complete. The pages are written in order, apart from the
+    /// header at the start, which is filled in last.
+    pub fn write_to<W: Write + Seek>(
+        out: W,
+        page_size: u32,
+        source: &mut dyn Iterator<Item = (Key, Value)>,
+    ) -> std::io::Result<W> {
+        let mut source = source.map(|(key, value)| Ok((key, value, None)));
+        BTree::write_leaves_to(out, page_size, LEAF_TYPE, 1.0, false, None, &mut source)
+    }
+
+    /// Writes a tree as `write_from_iterator` does, but into memory, returning the bytes the file would hold. They can
+    /// be opened with `from_reader`.
+    pub fn write_to_vec(
+        page_size: u32,
+        source: &mut dyn Iterator<Item = (Key, Value)>,
+    ) -> std::io::Result<Vec<u8>> {
+        let out = BTree::write_to(std::io::Cursor::new(Vec::new()), page_size, source)?;
+        Ok(out.into_inner())
+    }
+
+    /// Writes a new BTree file as `write_from_iterator` does, but with each inner page pointing to at most `fanout`
+    /// pages rather than as many as fit, to give the tree the height `recommended_fanout` picked the fanout for. The
+    /// leaves are still packed full. A fanout below what an inner page holds leaves the rest of each inner page empty
+    /// and takes more inner pages, and so a bigger file, as well as a tree that may be taller.
+    pub fn write_with_fanout(
+        file_name: &str,
+        page_size: u32,
+        fanout: usize,
+        source: &mut dyn Iterator<Item = (Key, Value)>,
+    ) -> std::io::Result<()> {
+        let mut source = source.map(|(key, value)| Ok((key, value, None)));
+        let (temp_file, file) = TempFile::create(file_name)?;
+        let file = BTree::write_leaves_to(
+            file,
             page_size,
-            page_count: 0,
-            root_page_num: 0,
+            LEAF_TYPE,
+            1.0,
+            false,
+            Some(fanout),
+            &mut source,
+        )?;
+        temp_file.persist(file)
+    }
+
+    /// Writes a new BTree file of values encoded with `V`, from an iterator that returns the keys and values to be
+    /// loaded in their key sorted order.
+    pub fn write_values_from_iterator<V: ValueCodec>(
+        file_name: &str,
+        page_size: u32,
+        source: &mut dyn Iterator<Item = (Key, V)>,
+    ) -> std::io::Result<()> {
+        BTree::write_values_with_fill_factor(file_name, page_size, 1.0, source)
+    }
+
+    /// Writes a new BTree file of values encoded with `V`, filling each leaf only to the given fraction of its
+    /// capacity so that there's room left for later inserts. A fill factor of 1 packs the leaves full, as
+    /// `write_values_from_iterator` does. The inner nodes are always packed full.
+    pub fn write_values_with_fill_factor<V: ValueCodec>(
+        file_name: &str,
+        page_size: u32,
+        fill_factor: f32,
+        source: &mut dyn Iterator<Item = (Key, V)>,
+    ) -> std::io::Result<()> {
+        let mut source = source.map(|(key, value)| Ok((key, value, None)));
+        BTree::write_leaves(
+            file_name,
+            page_size,
+            LEAF_TYPE,
+            fill_factor,
+            false,
+            &mut source,
+        )
+    }
+
+    /// Writes a new BTree file as `write_from_iterator` does, but with the values in each leaf delta encoded to fit
+    /// more keys in a leaf. Each value is stored as a 16 bit number of steps above the smallest value in its leaf,
+    /// where a step is 1/65535 of the range of the leaf's values, so a value reads back within half a step of what
+    /// was written. The values have to be finite. The keys are packed too, each stored as how far it is from the first
+    /// key in its leaf, which takes an entry from 20 bytes down to 10. A key too far from the first to be packed, such
+    /// as one for an asset more than 65535 ids on, or a timestamp more than 2^31 units away, starts a new leaf.
+    pub fn write_delta_encoded_from_iterator(
+        file_name: &str,
+        page_size: u32,
+        source: &mut dyn Iterator<Item = (Key, Value)>,
+    ) -> std::io::Result<()> {
+        let mut source = source.map(|(key, value)| Ok((key, value, None)));
+        BTree::write_leaves(file_name, page_size, LEAF_TYPE, 1.0, true, &mut source)
+    }
+
+    /// Writes a new BTree file whose rows carry effective ranges from an iterator that returns them in their key
+    /// sorted order. The versions of a key should be ordered by the start of their effective ranges.
+    pub fn write_bitemporal_from_iterator(
+        file_name: &str,
+        page_size: u32,
+        source: &mut dyn Iterator<Item = (Key, Value, EffectiveRange)>,
+    ) -> std::io::Result<()> {
+        let mut source = source.map(|(key, value, range)| Ok((key, value, Some(range))));
+        BTree::write_leaves(
+            file_name,
+            page_size,
+            BITEMPORAL_LEAF_TYPE,
+            1.0,
+            false,
+            &mut source,
+        )
+    }
+
+    /// Writes a new BTree file from keys and values in any order, sorting them without holding them all in memory. The
+    /// source is read in chunks of `chunk_rows` rows, each of which is sorted and spilled to a temporary file next to
+    /// `file_name`, and then the chunks are merged into the tree. Rows with the same key keep their order from the
+    /// source. The temporary files are removed whether or not the write succeeds.
+    pub fn write_from_unsorted_external(
+        file_name: &str,
+        page_size: u32,
+        source: &mut dyn Iterator<Item = (Key, Value)>,
+        chunk_rows: usize,
+    ) -> std::io::Result<()> {
+        if chunk_rows == 0 {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "Chunks must hold at least one row",
+            ));
+        }
+
+        let mut spill_files = SpillFiles { paths: Vec::new() };
+        let mut chunk = Vec::with_capacity(chunk_rows);
+        loop {
+            chunk.extend(source.take(chunk_rows));
+            if chunk.is_empty() {
+                break;
+            }
+            chunk.sort_by_key(|(key, _)| *key);
+            spill_files.write(file_name, chunk.drain(..))?;
+        }
+
+        let chunks = spill_files
+            .paths
+            .iter()
+            .enumerate()
+            .map(|(chunk_index, path)| SpillFiles::read(path, chunk_index))
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        // Ties between chunks go to the earlier chunk, and errors come out as soon as they're read.
+        let merged = chunks.into_iter().kmerge_by(|a, b| match (a, b) {
+            (Ok((a_key, a_chunk, _)), Ok((b_key, b_chunk, _))) => {
+                (a_key, a_chunk) < (b_key, b_chunk)
+            }
+            (a, _) => a.is_err(),
         });
-        file.write(&file_header_buf.buf)?;
+        let mut rows = merged.map(|row| row.map(|(key, _, value)| (key, value, None)));
+        BTree::write_leaves(file_name, page_size, LEAF_TYPE, 1.0, false, &mut rows)
+    }
+
+    fn write_leaves<V: ValueCodec>(
+        file_name: &str,
+        page_size: u32,
+        leaf_type: u32,
+        fill_factor: f32,
+        delta_encode: bool,
+        source: &mut dyn Iterator<Item = std::io::Result<(Key, V, Option<EffectiveRange>)>>,
+    ) -> std::io::Result<()> {
+        let (temp_file, file) = TempFile::create(file_name)?;
+        let file = BTree::write_leaves_to(
+            file,
+            page_size,
+            leaf_type,
+            fill_factor,
+            delta_encode,
+            None,
+            source,
+        )?;
+        temp_file.persist(file)
+    }
+
+    /// Writes a tree to the start of `out`, returning `out` once the tree is complete. Inner pages point to at most
+    /// `fanout` pages if it's given, and to as many as fit otherwise. The first error the source returns stops the
+    /// write and is returned in place of `out`.
+    fn write_leaves_to<W: Write + Seek, V: ValueCodec>(
+        out: W,
+        page_size: u32,
+        leaf_type: u32,
+        fill_factor: f32,
+        delta_encode: bool,
+        fanout: Option<usize>,
+        source: &mut dyn Iterator<Item = std::io::Result<(Key, V, Option<EffectiveRange>)>>,
+    ) -> std::io::Result<W> {
+        if !(fill_factor > 0.0 && fill_factor <= 1.0) {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Fill factor {} is not in (0, 1]", fill_factor),
+            ));
+        }
+
+        // A page has to fit at least one leaf entry, or one key of an inner page along with its two children.
+        let mut leaf_page_size = page_size_for_keys_of::<V>(1);
+        if leaf_type == BITEMPORAL_LEAF_TYPE {
+            leaf_page_size += EFFECTIVE_RANGE_SIZE;
+        }
+        if delta_encode {
+            // The leaf's first key is kept in full as well as packed into its entry.
+            leaf_page_size =
+                PAGE_HEADER_SIZE + DELTA_HEADER_SIZE + KEY_SIZE + PACKED_KEY_SIZE + DELTA_SIZE;
+        }
+        let inner_page_size = PAGE_HEADER_SIZE + INNER_ENTRY_SIZE;
+        check_page_size(
+            page_size,
+            leaf_page_size.max(inner_page_size),
+            BLOCK_SIZE,
+            false,
+        )?;
 
-        let mut leaf_buf = PageBuffer::new(page_size, LEAF_TYPE);
+        let mut writer = TreeWriter::create(out, page_size, leaf_type, V::ID, V::WIDTH)?;
+        if let Some(fanout) = fanout {
+            let max_fanout = (page_size as usize - PAGE_HEADER_SIZE) / INNER_ENTRY_SIZE + 1;
+            if !(2..=max_fanout).contains(&fanout) {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "Fanout {} is not in [2, {}] for a page size of {}",
+                        fanout, max_fanout, page_size
+                    ),
+                ));
+            }
+            writer.max_inner_keys = fanout - 1;
+        }
+        let mut leaf_buf = PageBuffer::new(page_size, leaf_type, V::WIDTH);
+        leaf_buf.set_flag(DELTA_ENCODED_FLAG, delta_encode);
+        leaf_buf.set_flag(PACKED_KEYS_FLAG, delta_encode);
+        let mut delta_values = Vec::new();
         let key_capacity = leaf_buf.key_capacity();
+        let key_capacity =
+            ((key_capacity as f32 * fill_factor).ceil() as usize).clamp(1, key_capacity);
 
-        let mut page_count = 0;
-        let mut last_leaf_page_num = u32::max_value();
-        let mut lineage: Vec<PageBuffer> = Vec::new();
+        let mut last_leaf_page_num = u32::MAX;
         let mut peekable_source = source.peekable();
 
-        while peekable_source.peek().is_some() {
-            if last_leaf_page_num < u32::max_value() {
-                let last_key = leaf_buf.key(0);
-                match BTree::add_to_parent(last_key, &mut page_count, 0, &mut lineage, page_size) {
-                    Some(filled_inner_pages) => {
-                        for page_buf in filled_inner_pages.iter().rev() {
-                            file.write(&page_buf.buf)?;
-                        }
-                    }
-                    _ => {}
-                }
-                page_count += 1;
+        // Always write at least one leaf, so that an empty source still produces a readable file.
+        while last_leaf_page_num == u32::MAX || peekable_source.peek().is_some() {
+            if last_leaf_page_num < u32::MAX {
+                // Only add the previous leaf to a parent once it's known to have a sibling.
+                writer.add_to_parent(leaf_buf.key(0), last_leaf_page_num, 0)?;
                 leaf_buf.clear();
             }
 
             // Read up to a leaf's worth of keys and values.
             let mut key_index = 0;
             while key_index < key_capacity {
+                if key_index > 0 && leaf_buf.has_packed_keys() {
+                    // A key too far from the first one in the leaf to be packed starts the next leaf.
+                    if let Some(Ok((key, _, _))) = peekable_source.peek() {
+                        if packed_key(&leaf_buf.key(0), key).is_none() {
+                            break;
+                        }
+                    }
+                }
                 match peekable_source.next() {
                     None => break,
-                    Some((key, value)) => {
+                    Some(row) => {
+                        let (key, value, effective_range) = row?;
                         leaf_buf.set_key(key_index, key);
-                        leaf_buf.set_value(key_index, value);
+                        if delta_encode {
+                            // Only f32 values are delta encoded.
+                            let mut buf = [0; size_of::<f32>()];
+                            value.encode(&mut buf);
+                            let value = f32::decode(&buf);
+                            if !value.is_finite() {
+                                return Err(std::io::Error::new(
+                                    ErrorKind::InvalidInput,
+                                    format!("Can't delta encode {} for {:?}", value, key),
+                                ));
+                            }
+                            delta_values.push(value);
+                        } else {
+                            leaf_buf.set_value(key_index, value);
+                        }
+                        if let Some(effective_range) = effective_range {
+                            leaf_buf.set_effective_range(key_index, effective_range);
+                        }
                         key_index += 1;
                         leaf_buf.set_num_keys(key_index as u32);
                     }
                 }
             }
-            leaf_buf.set_extra_page_num(last_leaf_page_num);
-            last_leaf_page_num = page_count;
-            file.write(&leaf_buf.buf)?;
-        }
-        page_count += 1;
-
-        // Write out any incomplete parent nodes, pushing its page number to its parent.
-        for index in 0..lineage.len() {
-            let last_key = leaf_buf.key(0);
-            let page_buf = &mut lineage[index];
-            let num_keys = page_buf.num_keys();
-            page_buf.set_key(num_keys as usize, last_key);
-            if num_keys < ((key_capacity - 1) as u32) {
-                page_buf.set_page_number((num_keys + 1) as usize, page_count - 1);
-            } else {
-                page_buf.set_extra_page_num(page_count - 1);
+            if delta_encode {
+                leaf_buf.set_delta_values(&delta_values);
+                delta_values.clear();
             }
-            page_buf.set_num_keys(num_keys + 1);
-            println!("{}", page_buf.page_type());
-            file.write(&page_buf.buf)?;
-            // page_buf.print();
+            leaf_buf.set_extra_page_num(last_leaf_page_num);
+            last_leaf_page_num = writer.write_page(&leaf_buf)?;
+        }
 
-            page_count += 1;
+        if !writer.lineage.is_empty() {
+            writer.add_to_parent(leaf_buf.key(0), last_leaf_page_num, 0)?;
         }
+        writer.finish()
+    }
 
-        file_header_buf.set(FileHeader {
-            page_size,
-            page_count: page_count as u32,
-            root_page_num: (page_count - 1) as u32,
-        });
-        file.seek(SeekFrom::Start(0))?;
-        file.write(&file_header_buf.buf)?;
-        return Ok(());
+    fn load_page(&mut self, page_num: PageNumber) -> std::io::Result<CachedPage<'_>> {
+        let buf = self.page_cache.load(page_num as usize)?;
+        Ok(CachedPage {
+            buf,
+            value_width: self.file_header.value_width as usize,
+            timestamp_width: self.file_header.timestamp_width(),
+        })
     }
 
-    fn add_to_parent(
-        key: Key,
-        page_number: &mut PageNumber,
-        index: usize,
-        lineage: &mut Vec<PageBuffer>,
-        page_size: u32,
-    ) -> Option<Vec<PageBuffer>> {
-        if index == lineage.len() {
-            let mut inner_buf = PageBuffer::new(page_size, INNER_TYPE);
-            inner_buf.set_page_number(0, *page_number);
-            lineage.push(inner_buf);
-            None
+    /// Fails unless the file's values were written with `V`, and are as wide as its values.
+    fn check_value_codec<V: ValueCodec>(&self) -> std::io::Result<()> {
+        let header = &self.file_header;
+        if header.value_codec == V::ID && header.value_width as usize == V::WIDTH {
+            Ok(())
         } else {
-            let num_keys = lineage[index].num_keys();
-            let key_capacity = lineage[index].key_capacity();
-            if num_keys < (key_capacity as u32) {
-                let inner_buf = &mut lineage[index];
-                inner_buf.set_key(num_keys as usize, key);
-                if num_keys < ((key_capacity - 1) as u32) {
-                    inner_buf.set_page_number((num_keys + 1) as usize, *page_number);
-                } else {
-                    inner_buf.set_extra_page_num(*page_number);
-                }
-                inner_buf.set_num_keys(num_keys + 1);
-                None
-            } else {
-                let new_inner_buf = PageBuffer::new(page_size, INNER_TYPE);
-                lineage.push(new_inner_buf);
-
-                let old_inner_buf = lineage.swap_remove(index);
-
-                *page_number += 1;
-                let res = BTree::add_to_parent(key, page_number, index + 1, lineage, page_size);
-                match res {
-                    None => Some(vec![old_inner_buf]),
-                    Some(mut vec) => {
-                        vec.push(old_inner_buf);
-                        Some(vec)
-                    }
-                }
-            }
+            Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "File values have codec {} and width {}, not {} and {}",
+                    header.value_codec,
+                    header.value_width,
+                    V::ID,
+                    V::WIDTH
+                ),
+            ))
         }
     }
 
-    pub fn query(&mut self, query: Query) -> std::io::Result<QueryResultIterator> {
+    /// Descends from the root along the last child of each inner page, returning the page number of the last leaf.
+    fn last_leaf(&mut self) -> std::io::Result<PageNumber> {
         let mut page_num = self.file_header.root_page_num;
-        let mut page = self.page_cache.load(page_num as usize)?;
+        let mut page = self.load_page(page_num)?;
+        while !page.is_leaf() {
+            page_num = *page.children().last().unwrap();
+            page = self.load_page(page_num)?;
+        }
+        Ok(page_num)
+    }
 
-        let key = Key {
-            asset_id: query.asset_id,
-            date: query.end_date,
-            timestamp: query.timestamp,
-        };
-        while page.page_type() == INNER_TYPE {
-            let index = page.index_of(&key) as usize;
+    /// Descends from the root to the leaf that would hold the key, returning its page number. A tree whose keys fit in
+    /// a single leaf has no inner pages, and its root is that leaf.
+    fn find_leaf(&mut self, key: &Key) -> std::io::Result<PageNumber> {
+        let mut page_num = self.file_header.root_page_num;
+        let mut page = self.load_page(page_num)?;
+        while !page.is_leaf() {
+            let index = page.index_of(key) as usize;
             page_num = if index < page.key_capacity() {
                 page.page_number(index)
             } else {
                 page.extra_page_num()
             };
 
-            page = self.page_cache.load(page_num as usize)?;
+            page = self.load_page(page_num)?;
         }
+        Ok(page_num)
+    }
+
+    pub fn query(&mut self, query: Query) -> std::io::Result<QueryResultIterator<'_>> {
+        self.query_values(query)
+    }
+
+    /// Runs a query over a file of values encoded with `V`.
+    pub fn query_values<V: ValueCodec>(
+        &mut self,
+        query: Query,
+    ) -> std::io::Result<QueryResultIterator<'_, V>> {
+        self.check_value_codec::<V>()?;
+        let key = Key {
+            asset_id: query.asset_id,
+            date: query.end_date,
+            timestamp: query.latest_timestamp(),
+        };
+        let page_num = self.find_leaf(&key)?;
+        let page = self.load_page(page_num)?;
 
-        let key_index = min(page.index_of(&key), page.num_keys() - 1);
+        // Only an empty tree has an empty leaf.
+        let key_index = match page.num_keys() {
+            0 => None,
+            num_keys => Some(min(page.index_of(&key), num_keys - 1) as usize),
+        };
         Ok(QueryResultIterator::new(
             &mut self.page_cache,
+            self.file_header.value_width as usize,
+            self.file_header.timestamp_width(),
             query,
             page_num,
             key_index,
         ))
     }
 
-    fn print(&mut self) -> std::io::Result<()> {
-        let file_header = &self.file_header;
-        println!("Header: {:?}", file_header);
-        println!("---");
-        for i in 0..file_header.page_count {
-            println!("Page number: {}", i);
-            self.page_cache.load(i as usize)?.print();
-            println!("---");
-        }
-        Ok(())
+    /// Looks up the latest row for an asset and date as of a timestamp, without setting up a range query. Returns
+    /// None if the date has no rows for the asset at or before the timestamp.
+    pub fn get_latest(
+        &mut self,
+        asset_id: AssetId,
+        date: Date,
+        timestamp: Timestamp,
+    ) -> std::io::Result<Option<QueryResult>> {
+        self.get_latest_value(asset_id, date, timestamp)
     }
 
-    // pub fn bulk_query(&self, _queries: &Vec<Query>) -> QueryResultIterator {
-    //     QueryResultIterator {}
-    // }
-}
-
-pub struct QueryResultIterator<'a> {
-    page_cache: &'a mut PageCache,
-    page_num: u32,
-    key_index: Option<u32>,
-    query: Query,
-    last_yielded_date: Option<u32>,
-    pages_read: u32,
-}
-
-enum QueryResultIteratorState {
-    Continue,
-    YieldResult(Option<QueryResult>),
-}
-
-// impl<'a> Iterator for QueryResultIterator<'a> {
-//     type Item = std::io::Result<QueryResult>;
-//
-//
-// }
+    /// Looks up the latest row for an asset and date as of a timestamp in a file of values encoded with `V`.
+    pub fn get_latest_value<V: ValueCodec>(
+        &mut self,
+        asset_id: AssetId,
+        date: Date,
+        timestamp: Timestamp,
+    ) -> std::io::Result<Option<QueryResult<V>>> {
+        let mut results = self.query_point_values(&[(asset_id, date)], timestamp)?;
+        Ok(results.pop().flatten())
+    }
 
-impl<'a> QueryResultIterator<'a> {
-    fn new(
-        page_cache: &'a mut PageCache,
-        query: Query,
-        page_num: u32,
-        key_index: u32,
-    ) -> QueryResultIterator<'a> {
-        QueryResultIterator {
-            page_cache,
-            page_num,
-            key_index: Some(key_index),
-            query,
-            last_yielded_date: None,
-            pages_read: 1,
+    /// Looks up the latest value as of a timestamp for an asset on a date, or failing that on the nearest earlier date
+    /// up to `max_back` of the calendar's open days before it. Returns the date the value was found on, the value, and
+    /// how many open days back that date is. Rows on days the calendar has closed are passed over. A single range
+    /// query finds the value, so the missing days cost nothing to skip over.
+    pub fn query_nearest(
+        &mut self,
+        asset_id: AssetId,
+        date: Date,
+        timestamp: Timestamp,
+        max_back: u32,
+        calendar: &Calendar,
+    ) -> std::io::Result<Option<(Date, Value, u32)>> {
+        let latest_open_day = match Some(date)
+            .filter(|date| calendar.is_open(*date))
+            .or_else(|| calendar.previous_open_day(date))
+        {
+            Some(latest_open_day) => latest_open_day,
+            None => return Ok(None),
+        };
+        // The query reads back from the date until it reaches a row on an open day, and only then are the open days
+        // back to that row counted, stopping once there are more than `max_back` of them.
+        for result in self.query(Query::new(0, asset_id, Date(0), date, timestamp))? {
+            let result = result?;
+            if !calendar.is_open(result.key.date) {
+                continue;
+            }
+            let days_back = std::iter::successors(Some(latest_open_day), |date| {
+                calendar.previous_open_day(*date)
+            })
+            .take_while(|date| *date > result.key.date)
+            .take((max_back as usize).saturating_add(1))
+            .count() as u32;
+            return Ok(
+                Some((result.key.date, result.value, days_back)).filter(|_| days_back <= max_back)
+            );
         }
+        Ok(None)
     }
 
-    fn next(&mut self) -> Option<std::io::Result<QueryResult>> {
-        let mut state = Ok(QueryResultIteratorState::Continue);
-
-        while let Ok(QueryResultIteratorState::Continue) = state {
-            state = self.iterate()
+    /// Returns a value as of a timestamp for each of the calendar's open days from `start_date` to `end_date`: the
+    /// asset's value on that day, or failing that the one carried forward from the latest earlier open day with a
+    /// value. Days before the asset's first value are left out. Days the calendar has closed get no value, and any
+    /// rows on them are passed over, so a weekend or holiday isn't filled in, or carried forward from.
+    pub fn forward_fill(
+        &mut self,
+        asset_id: AssetId,
+        start_date: Date,
+        end_date: Date,
+        timestamp: Timestamp,
+        calendar: &Calendar,
+    ) -> std::io::Result<Vec<(Date, Value)>> {
+        // The results come back from the latest date, so the query reads back until it has the value carried into
+        // the start date.
+        let mut rows = Vec::new();
+        for result in self.query(Query::new(0, asset_id, Date(0), end_date, timestamp))? {
+            let result = result?;
+            if calendar.is_open(result.key.date) {
+                rows.push((result.key.date, result.value));
+                if result.key.date < start_date {
+                    break;
+                }
+            }
         }
 
-        match state {
-            Ok(QueryResultIteratorState::YieldResult(Some(result))) => {
-                self.last_yielded_date = Some(result.key.date);
-                Some(Ok(result))
+        let mut rows = rows.into_iter().rev().peekable();
+        let mut carried = None;
+        let mut filled = Vec::new();
+        for date in calendar.open_days(start_date, end_date) {
+            while let Some((_, value)) = rows.next_if(|(row_date, _)| *row_date <= date) {
+                carried = Some(value);
+            }
+            if let Some(value) = carried {
+                filled.push((date, value));
             }
-            Ok(QueryResultIteratorState::YieldResult(None)) => None,
-            Err(e) => Some(Err(e)),
-            _ => None,
         }
+        Ok(filled)
     }
 
-    fn iterate(&mut self) -> std::io::Result<QueryResultIteratorState> {
-        let page = self.page_cache.load(self.page_num as usize)?;
-        match self.key_index {
-            None if page.extra_page_num() == u32::max_value() => {
-                Ok(QueryResultIteratorState::YieldResult(None))
-            }
-            None => {
-                self.page_num = page.extra_page_num();
-                self.pages_read += 1;
+    /// Whether there are any rows for an asset and date, whatever their timestamps. Only the leaf where the latest of
+    /// them would be is read, along with the leaf before it if they'd end that one.
+    pub fn contains(&mut self, asset_id: AssetId, date: Date) -> std::io::Result<bool> {
+        let key = Key::new(asset_id, date, Timestamp(u64::MAX));
+        let last = match self.last_entry_at_or_before(&key)? {
+            Some((page_num, index)) => Some(self.load_page(page_num)?.key(index)),
+            None => None,
+        };
+        Ok(last.is_some_and(|last| last.asset_id == asset_id && last.date == date))
+    }
 
-                let page = self.page_cache.load(self.page_num as usize)?;
-                let num_keys = page.num_keys();
-                self.key_index = Some(num_keys - 1);
-                Ok(QueryResultIteratorState::Continue)
-            }
-            Some(key_index) => {
-                let key = page.key(key_index as usize);
-                if key.asset_id < self.query.asset_id || key.date < self.query.start_date {
-                    Ok(QueryResultIteratorState::YieldResult(None))
-                } else {
-                    self.key_index = if key_index == 0 {
-                        None
-                    } else {
-                        Some(key_index - 1)
-                    };
-                    match self.last_yielded_date {
-                        None if key.asset_id > self.query.asset_id
-                            || key.date > self.query.end_date
-                            || key.timestamp > self.query.timestamp =>
-                        {
-                            Ok(QueryResultIteratorState::Continue)
-                        }
-                        Some(d) if d == key.date || key.timestamp > self.query.timestamp => {
-                            Ok(QueryResultIteratorState::Continue)
-                        }
-                        _ => Ok(QueryResultIteratorState::YieldResult(Some(QueryResult {
-                            id: self.query.id,
-                            key,
-                            value: page.value(key_index as usize),
-                        }))),
-                    }
-                }
+    /// Looks up the value of the row with exactly the given key, or None if there's no such row. Unlike
+    /// `get_latest`, a row with an earlier timestamp doesn't stand in for a missing one. Of rows repeating the key,
+    /// the last is returned.
+    pub fn get(
+        &mut self,
+        asset_id: AssetId,
+        date: Date,
+        timestamp: Timestamp,
+    ) -> std::io::Result<Option<Value>> {
+        self.check_value_codec::<Value>()?;
+        let key = Key::new(asset_id, date, timestamp);
+        match self.last_entry_at_or_before(&key)? {
+            Some((page_num, index)) => {
+                let page = self.load_page(page_num)?;
+                Ok(Some(page.value(index)).filter(|_| page.key(index) == key))
             }
+            None => Ok(None),
         }
     }
-}
 
-fn read_u32(buf: &[u8]) -> u32 {
-    let (int_bytes, _) = buf.split_at(U32_SIZE);
-    return u32::from_be_bytes(int_bytes.try_into().unwrap());
-}
+    /// Finds the leaf and index of the last entry whose key is at most the given key, which is either in the leaf
+    /// the key would be in or at the end of the leaf before it.
+    fn last_entry_at_or_before(
+        &mut self,
+        key: &Key,
+    ) -> std::io::Result<Option<(PageNumber, usize)>> {
+        let page_num = self.find_leaf(key)?;
+        let page = self.load_page(page_num)?;
+        let index = page.index_of(key) as usize;
+        let previous_leaf = page.extra_page_num();
+        if index > 0 {
+            Ok(Some((page_num, index - 1)))
+        } else if previous_leaf != u32::MAX {
+            let num_keys = self.load_page(previous_leaf)?.num_keys() as usize;
+            Ok(Some((previous_leaf, num_keys - 1)))
+        } else {
+            Ok(None)
+        }
+    }
 
-fn write_u32(buf: &mut [u8], source: u32) {
-    buf[0..U32_SIZE].copy_from_slice(&source.to_be_bytes()[..])
-}
+    /// Looks up the latest row as of a timestamp for each of a set of assets and dates, returning the results in the
+    /// order of the points, with None for each point that has no rows at or before the timestamp. Each result's id
+    /// is the index of its point.
+    pub fn query_points(
+        &mut self,
+        points: &[(AssetId, Date)],
+        timestamp: Timestamp,
+    ) -> std::io::Result<Vec<Option<QueryResult>>> {
+        self.query_point_values(points, timestamp)
+    }
 
-fn read_f32(buf: &[u8]) -> f32 {
-    let (float_bytes, _) = buf.split_at(size_of::<f32>());
-    return f32::from_be_bytes(float_bytes.try_into().unwrap());
-}
+    /// Looks up the latest row as of a timestamp for each of a set of assets and dates in a file of values encoded
+    /// with `V`.
+    pub fn query_point_values<V: ValueCodec>(
+        &mut self,
+        points: &[(AssetId, Date)],
+        timestamp: Timestamp,
+    ) -> std::io::Result<Vec<Option<QueryResult<V>>>> {
+        self.check_value_codec::<V>()?;
+        let mut results: Vec<Option<QueryResult<V>>> = points.iter().map(|_| None).collect();
 
-fn write_f32(buf: &mut [u8], source: f32) {
-    buf[0..size_of::<f32>()].copy_from_slice(&source.to_be_bytes()[..])
-}
+        // Visit the points in key order, so that the points sharing a leaf are found with a single load of it.
+        let mut order: Vec<usize> = (0..points.len()).collect();
+        order.sort_by_key(|&index| points[index]);
 
-pub fn read_csv(file_name: &str) -> Box<dyn Iterator<Item = (Key, Value)>> {
-    let file = File::open(file_name).unwrap();
-    let reader = BufReader::new(file);
+        let mut leaf_buf = PageBuffer {
+            buf: Vec::new(),
+            value_width: self.file_header.value_width as usize,
+            timestamp_width: self.file_header.timestamp_width(),
+        };
+        for point_index in order {
+            let (asset_id, date) = points[point_index];
+            let key = Key::new(asset_id, date, timestamp);
 
-    Box::new(reader.lines().map(|line| {
-        let line = line.unwrap();
-        let mut columns = line.split(",");
-        let asset_id = columns.next().map(|r| u32::from_str(r).unwrap()).unwrap();
-        let date = columns.next().map(|r| u32::from_str(r).unwrap()).unwrap();
-        let timestamp = columns.next().map(|r| u32::from_str(r).unwrap()).unwrap();
-        let value = columns.next().map(|r| f32::from_str(r).unwrap()).unwrap();
-        (Key::new(asset_id, date, timestamp), value)
-    }))
-}
+            // The latest row at or before the key sits just before the first key greater than it. When that's
+            // strictly inside the current leaf, both are in it; otherwise descend from the root again.
+            let in_leaf = !leaf_buf.buf.is_empty() && {
+                let index = leaf_buf.index_of(&key);
+                index > 0 && index < leaf_buf.num_keys()
+            };
+            if !in_leaf {
+                let page_num = self.find_leaf(&key)?;
+                let page = self.page_cache.load(page_num as usize)?;
+                leaf_buf.buf.clear();
+                leaf_buf.buf.extend_from_slice(page);
+            }
 
-#[cfg(test)]
-mod tests {
-    use crate::btree::file::{page_size_for_keys, BTree, Key, PageBuffer, Query, QueryResult};
-    use std::fs;
-    use std::fs::File;
-    use std::io::Error;
+            // When the first key greater than it starts the leaf, the row is the previous leaf's last.
+            let index = leaf_buf.index_of(&key) as usize;
+            let found = if index > 0 {
+                Some((leaf_buf.key(index - 1), leaf_buf.value(index - 1)))
+            } else if leaf_buf.extra_page_num() != u32::MAX {
+                let page = self.load_page(leaf_buf.extra_page_num())?;
+                let last = page.num_keys() as usize - 1;
+                Some((page.key(last), page.value(last)))
+            } else {
+                None
+            };
+            results[point_index] = found
+                .filter(|(found, _)| found.asset_id == asset_id && found.date == date)
+                .map(|(key, value)| QueryResult {
+                    id: point_index,
+                    key,
+                    value,
+                });
+        }
+        Ok(results)
+    }
+
+    /// Continues a query from a cursor checkpointed by an iterator over the same query, yielding the results that
+    /// iterator hadn't yet returned. Fails unless the file's values were written with `V`.
+    pub fn resume<V: ValueCodec>(
+        &mut self,
+        cursor: Cursor,
+        query: Query,
+    ) -> std::io::Result<QueryResultIterator<'_, V>> {
+        self.check_value_codec::<V>()?;
+        let mut iterator = QueryResultIterator::new(
+            &mut self.page_cache,
+            self.file_header.value_width as usize,
+            self.file_header.timestamp_width(),
+            query,
+            cursor.page_num,
+            cursor.key_index,
+        );
+        iterator.last_yielded_date = cursor.last_yielded_date;
+        iterator.periods = cursor.periods;
+        Ok(iterator)
+    }
+
+    /// Checks the structure of the tree, returning every problem found: child pointers out of range or reaching a
+    /// page twice, pages with more keys than fit, keys out of order within a page or outside the range allowed by
+    /// their parent's separator keys, leaves at different depths, and leaves whose back links don't run through the
+    /// leaves in order to the first one. Repeated keys are allowed, since they're the corrected versions of a row.
+    pub fn fsck(&mut self) -> Result<(), Vec<String>> {
+        let mut check = TreeCheck::default();
+        let root_page_num = self.file_header.root_page_num;
+        if let Err(e) = self.check_page(&mut check, root_page_num, None, None, 0) {
+            check.problems.push(format!("Failed to read page: {}", e));
+        }
+
+        let mut previous_leaf = u32::MAX;
+        for &(page_num, extra_page_num) in check.leaves.iter() {
+            if extra_page_num != previous_leaf {
+                check.problems.push(format!(
+                    "Leaf {} links back to page {} rather than {}",
+                    page_num, extra_page_num, previous_leaf
+                ));
+            }
+            previous_leaf = page_num;
+        }
+
+        if check.problems.is_empty() {
+            Ok(())
+        } else {
+            Err(check.problems)
+        }
+    }
+
+    /// Checks the page and the subtree under it, whose keys should fall between the given separator keys.
+    fn check_page(
+        &mut self,
+        check: &mut TreeCheck,
+        page_num: PageNumber,
+        lower: Option<Key>,
+        upper: Option<Key>,
+        depth: usize,
+    ) -> std::io::Result<()> {
+        if page_num >= self.file_header.page_count {
+            check.problems.push(format!(
+                "Page {} is out of range of the {} pages in the file",
+                page_num, self.file_header.page_count
+            ));
+            return Ok(());
+        }
+        if !check.visited.insert(page_num) {
+            check
+                .problems
+                .push(format!("Page {} is reached more than once", page_num));
+            return Ok(());
+        }
+
+        let leaf_type = self.file_header.leaf_type;
+        let page = self.load_page(page_num)?;
+        let page_type = page.page_type();
+        if page_type != INNER_TYPE && page_type != leaf_type {
+            check.problems.push(format!(
+                "Page {} has type {} rather than {} or {}",
+                page_num, page_type, INNER_TYPE, leaf_type
+            ));
+            return Ok(());
+        }
+        let num_keys = page.num_keys() as usize;
+        if num_keys > page.key_capacity() {
+            check.problems.push(format!(
+                "Page {} has {} keys, more than its capacity of {}",
+                page_num,
+                num_keys,
+                page.key_capacity()
+            ));
+            return Ok(());
+        }
+
+        if let Err(index) = page.check_key_order() {
+            check.problems.push(format!(
+                "Key {} in page {} is less than the key before it",
+                index, page_num
+            ));
+        }
+        let keys: Vec<Key> = (0..num_keys).map(|index| page.key(index)).collect();
+        for (index, key) in keys.iter().enumerate() {
+            if lower.is_some_and(|lower| *key < lower) || upper.is_some_and(|upper| *key > upper) {
+                check.problems.push(format!(
+                    "Key {} in page {} is outside the range of its parent's separator keys",
+                    index, page_num
+                ));
+            }
+        }
+
+        if page.is_leaf() {
+            match check.leaf_depth {
+                None => check.leaf_depth = Some(depth),
+                Some(leaf_depth) if leaf_depth != depth => check.problems.push(format!(
+                    "Leaf {} is at depth {} rather than {}",
+                    page_num, depth, leaf_depth
+                )),
+                _ => {}
+            }
+            check.leaves.push((page_num, page.extra_page_num()));
+        } else {
+            for (index, child) in page.children().into_iter().enumerate() {
+                let child_lower = if index == 0 {
+                    lower
+                } else {
+                    Some(keys[index - 1])
+                };
+                let child_upper = if index == num_keys {
+                    upper
+                } else {
+                    Some(keys[index])
+                };
+                self.check_page(check, child, child_lower, child_upper, depth + 1)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns each key that's stored more than once, in key order. Repeated keys are allowed by `fsck`, since a query
+    /// only returns the last of them, but they usually mean the same row was loaded twice. Every leaf is read, from
+    /// the last back to the first.
+    pub fn find_duplicates(&mut self) -> std::io::Result<Vec<Key>> {
+        let mut page_num = self.last_leaf()?;
+        let mut duplicates = Vec::new();
+        let mut next_key: Option<Key> = None;
+        while page_num != u32::MAX {
+            let page = self.load_page(page_num)?;
+            for index in (0..page.num_keys() as usize).rev() {
+                let key = page.key(index);
+                if next_key == Some(key) && duplicates.last() != Some(&key) {
+                    duplicates.push(key);
+                }
+                next_key = Some(key);
+            }
+            page_num = page.extra_page_num();
+        }
+        duplicates.reverse();
+        Ok(duplicates)
+    }
+
+    /// Returns the separator keys of the inner pages at a level of the tree, in order, where the root is level 0. They
+    /// summarize how the keys are spread without reading any leaves. Fails if the level is at or below the leaves.
+    pub fn level_keys(&mut self, level: usize) -> std::io::Result<Vec<Key>> {
+        let mut page_nums = vec![self.file_header.root_page_num];
+        let mut depth = 0;
+        loop {
+            let mut keys = Vec::new();
+            let mut children = Vec::new();
+            for &page_num in page_nums.iter() {
+                let page = self.load_page(page_num)?;
+                if page.is_leaf() {
+                    return Err(std::io::Error::new(
+                        ErrorKind::InvalidInput,
+                        format!(
+                            "Level {} is at or below the leaves at level {}",
+                            level, depth
+                        ),
+                    ));
+                }
+                keys.extend((0..page.num_keys() as usize).map(|index| page.key(index)));
+                children.extend(page.children());
+            }
+            if depth == level {
+                return Ok(keys);
+            }
+            page_nums = children;
+            depth += 1;
+        }
+    }
+
+    /// Returns the number of levels of pages from the root down to the leaves, so a tree that fits in one leaf has a
+    /// height of 1.
+    pub fn height(&mut self) -> std::io::Result<usize> {
+        let mut page = self.load_page(self.file_header.root_page_num)?;
+        let mut height = 1;
+        while !page.is_leaf() {
+            let page_num = page.page_number(0);
+            page = self.load_page(page_num)?;
+            height += 1;
+        }
+        Ok(height)
+    }
+
+    /// Reads every page of the file to report how full its leaves are and how many children its inner pages have.
+    pub fn fill_stats(&mut self) -> std::io::Result<FillStats> {
+        let mut occupancies = Vec::new();
+        let mut slack_bytes = 0;
+        let mut inner_fanout = BTreeMap::new();
+        for page_num in 0..self.file_header.page_count {
+            let page = self.load_page(page_num)?;
+            let num_keys = page.num_keys() as usize;
+            if page.is_leaf() {
+                occupancies.push(num_keys as f64 / page.key_capacity() as f64);
+                slack_bytes += (page.buf().len() - page.key_offset(num_keys)) as u64;
+            } else {
+                *inner_fanout.entry(num_keys + 1).or_insert(0) += 1;
+            }
+        }
+
+        Ok(FillStats {
+            leaves: occupancies.len(),
+            mean_occupancy: occupancies.iter().sum::<f64>() / occupancies.len() as f64,
+            min_occupancy: occupancies.iter().copied().fold(f64::INFINITY, f64::min),
+            max_occupancy: occupancies.iter().copied().fold(0.0, f64::max),
+            slack_bytes,
+            inner_fanout,
+        })
+    }
+
+    pub fn print(&mut self) -> std::io::Result<()> {
+        println!("Header: {:?}", self.file_header);
+        println!("---");
+        let format_value = match self.file_header.value_codec {
+            <f64 as ValueCodec>::ID => format_value::<f64>,
+            <i32 as ValueCodec>::ID => format_value::<i32>,
+            DictionaryCode::ID => format_value::<DictionaryCode>,
+            _ => format_value::<f32>,
+        };
+        for i in 0..self.file_header.page_count {
+            println!("Page number: {}", i);
+            self.load_page(i)?.print(format_value);
+            println!("---");
+        }
+        Ok(())
+    }
+
+    // pub fn bulk_query(&self, _queries: &Vec<Query>) -> QueryResultIterator {
+    //     QueryResultIterator {}
+    // }
+}
+
+/// What `BTree::fsck` has found so far, along with the leaves it has reached, in key order, paired with the pages
+/// they link back to.
+#[derive(Default)]
+struct TreeCheck {
+    problems: Vec<String>,
+    visited: HashSet<PageNumber>,
+    leaves: Vec<(PageNumber, PageNumber)>,
+    leaf_depth: Option<usize>,
+}
+
+/// The sorted chunks spilled by `write_from_unsorted_external`, which are removed when it's done with them.
+struct SpillFiles {
+    paths: Vec<String>,
+}
+
+impl SpillFiles {
+    /// Writes a sorted chunk of rows to a new file named after the tree being written.
+    fn write(
+        &mut self,
+        file_name: &str,
+        rows: impl Iterator<Item = (Key, Value)>,
+    ) -> std::io::Result<()> {
+        let path = format!("{}.chunk{}", file_name, self.paths.len());
+        self.paths.push(path.clone());
+        let mut writer = BufWriter::new(File::create(&path)?);
+        let mut buf = [0; KEY_VALUE_SIZE];
+        for (key, value) in rows {
+            write_u32(&mut buf[0..], key.asset_id.0);
+            write_u32(&mut buf[U32_SIZE..], key.date.0);
+            write_u64(&mut buf[2 * U32_SIZE..], key.timestamp.0);
+            value.encode(&mut buf[KEY_SIZE..]);
+            writer.write_all(&buf)?;
+        }
+        writer.flush()
+    }
+
+    /// Reads back a chunk's rows, each tagged with the chunk's index.
+    fn read(
+        path: &str,
+        chunk_index: usize,
+    ) -> std::io::Result<impl Iterator<Item = std::io::Result<(Key, usize, Value)>>> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut buf = [0; KEY_VALUE_SIZE];
+        Ok(std::iter::from_fn(move || {
+            match reader.read_exact(&mut buf) {
+                Ok(()) => {
+                    let key = Key::new(
+                        AssetId(read_u32(&buf[0..])),
+                        Date(read_u32(&buf[U32_SIZE..])),
+                        Timestamp(read_u64(&buf[2 * U32_SIZE..])),
+                    );
+                    Some(Ok((key, chunk_index, Value::decode(&buf[KEY_SIZE..]))))
+                }
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => None,
+                Err(e) => Some(Err(e)),
+            }
+        }))
+    }
+}
+
+impl Drop for SpillFiles {
+    fn drop(&mut self) {
+        for path in self.paths.iter() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// A file written beside its target, which is only renamed to the target once the whole tree has been written and
+/// synced, so that a failed or interrupted write never leaves a partial tree at the target. The temporary file is
+/// removed if it's dropped before then.
+struct TempFile {
+    file_name: String,
+    temp_file_name: String,
+    renamed: bool,
+}
+
+impl TempFile {
+    fn create(file_name: &str) -> std::io::Result<(TempFile, File)> {
+        let temp_file_name = format!("{}.tmp", file_name);
+        let file = File::create(&temp_file_name)?;
+        let temp_file = TempFile {
+            file_name: file_name.to_string(),
+            temp_file_name,
+            renamed: false,
+        };
+        Ok((temp_file, file))
+    }
+
+    /// Syncs the written file and moves it to the target.
+    fn persist(mut self, file: File) -> std::io::Result<()> {
+        file.sync_all()?;
+        std::fs::rename(&self.temp_file_name, &self.file_name)?;
+        self.renamed = true;
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.renamed {
+            let _ = std::fs::remove_file(&self.temp_file_name);
+        }
+    }
+}
+
+/// Writes the pages of a new BTree file in order, keeping the inner nodes along the right edge of the tree that are
+/// still being filled. Each entry in the lineage is paired with the first key of its subtree, which becomes its
+/// separator key once it's added to its own parent.
+struct TreeWriter<W: Write + Seek> {
+    out: W,
+    page_size: u32,
+    leaf_type: u32,
+    value_codec: u32,
+    value_width: usize,
+    page_count: u32,
+    lineage: Vec<(Key, PageBuffer)>,
+    /// The most keys an inner page is filled with before it's written out, which is as many as fit unless a smaller
+    /// fanout was asked for.
+    max_inner_keys: usize,
+    /// Inner page buffers that have been written out, kept to be cleared and reused for the next inner pages.
+    free_inner_bufs: Vec<PageBuffer>,
+}
+
+impl<W: Write + Seek> TreeWriter<W> {
+    fn create(
+        out: W,
+        page_size: u32,
+        leaf_type: u32,
+        value_codec: u32,
+        value_width: usize,
+    ) -> std::io::Result<TreeWriter<W>> {
+        let mut out = out;
+        update_header(
+            &mut out,
+            FileHeader {
+                format_version: FORMAT_VERSION,
+                page_size,
+                page_count: 0,
+                root_page_num: 0,
+                leaf_type,
+                value_codec,
+                value_width: value_width as u32,
+                flags: 0,
+            },
+        )?;
+        out.seek(SeekFrom::Start(FILE_HEADER_SIZE as u64))?;
+
+        Ok(TreeWriter {
+            out,
+            page_size,
+            leaf_type,
+            value_codec,
+            value_width,
+            page_count: 0,
+            lineage: Vec::new(),
+            max_inner_keys: usize::MAX,
+            free_inner_bufs: Vec::new(),
+        })
+    }
+
+    /// Appends a page to the file, returning its page number.
+    fn write_page(&mut self, page_buf: &PageBuffer) -> std::io::Result<PageNumber> {
+        debug_assert_eq!(
+            page_buf.check_key_order(),
+            Ok(()),
+            "Keys out of order in page {}",
+            self.page_count
+        );
+        if cfg!(debug_assertions) {
+            let offset = FILE_HEADER_SIZE as u64 + self.page_count as u64 * self.page_size as u64;
+            debug_assert_eq!(
+                offset,
+                self.out.stream_position()?,
+                "Page {} isn't being written at its offset",
+                self.page_count
+            );
+        }
+        self.out.write_all(&page_buf.buf)?;
+        self.page_count += 1;
+        Ok(self.page_count - 1)
+    }
+
+    /// Returns an empty inner page buffer, reusing one that's already been written out if there is one.
+    fn new_inner_buf(&mut self) -> PageBuffer {
+        match self.free_inner_bufs.pop() {
+            Some(mut inner_buf) => {
+                inner_buf.clear();
+                inner_buf
+            }
+            None => PageBuffer::new(self.page_size, INNER_TYPE, self.value_width),
+        }
+    }
+
+    /// Adds the page with the given first key to the inner node at the given level of the lineage. If that node is
+    /// full, it's written out and replaced with a new node, and the full node is added to its own parent in turn.
+    fn add_to_parent(
+        &mut self,
+        key: Key,
+        page_num: PageNumber,
+        index: usize,
+    ) -> std::io::Result<()> {
+        if index == self.lineage.len() {
+            let mut inner_buf = self.new_inner_buf();
+            inner_buf.set_page_number(0, page_num);
+            self.lineage.push((key, inner_buf));
+            return Ok(());
+        }
+
+        let inner_buf = &mut self.lineage[index].1;
+        let num_keys = inner_buf.num_keys();
+        let key_capacity = inner_buf.key_capacity() as u32;
+        if num_keys < key_capacity.min(self.max_inner_keys as u32) {
+            inner_buf.set_key(num_keys as usize, key);
+            if num_keys + 1 < key_capacity {
+                inner_buf.set_page_number((num_keys + 1) as usize, page_num);
+            } else {
+                inner_buf.set_extra_page_num(page_num);
+            }
+            inner_buf.set_num_keys(num_keys + 1);
+            Ok(())
+        } else {
+            let mut new_inner_buf = self.new_inner_buf();
+            new_inner_buf.set_page_number(0, page_num);
+            let (first_key, full_inner_buf) =
+                std::mem::replace(&mut self.lineage[index], (key, new_inner_buf));
+            let full_page_num = self.write_page(&full_inner_buf)?;
+            self.free_inner_bufs.push(full_inner_buf);
+            self.add_to_parent(first_key, full_page_num, index + 1)
+        }
+    }
+
+    /// Writes out the incomplete inner nodes from the bottom up, pushing each one's page number to its parent, then
+    /// fills in the file header with the final page count and the root page.
+    fn finish(mut self) -> std::io::Result<W> {
+        while !self.lineage.is_empty() {
+            let (first_key, inner_buf) = self.lineage.remove(0);
+            let page_num = self.write_page(&inner_buf)?;
+            self.free_inner_bufs.push(inner_buf);
+            if !self.lineage.is_empty() {
+                self.add_to_parent(first_key, page_num, 0)?;
+            }
+        }
+
+        update_header(
+            &mut self.out,
+            FileHeader {
+                format_version: FORMAT_VERSION,
+                page_size: self.page_size,
+                page_count: self.page_count,
+                root_page_num: self.page_count - 1,
+                leaf_type: self.leaf_type,
+                value_codec: self.value_codec,
+                value_width: self.value_width as u32,
+                flags: 0,
+            },
+        )?;
+        Ok(self.out)
+    }
+}
+
+/// Iterates over a query's results from the latest date to the earliest. The current leaf is copied out of the page
+/// cache when the iterator reaches it, so that the results within a leaf don't each go back to the cache, or to the
+/// file when there's no cache.
+pub struct QueryResultIterator<'a, V: ValueCodec = Value> {
+    page_cache: &'a mut PageCache,
+    page_num: u32,
+    key_index: Option<usize>,
+    query: Query,
+    last_yielded_date: Option<Date>,
+    /// The number of distinct dates results have been returned for, counted against the query's `max_periods`.
+    periods: u32,
+    leaf_buf: PageBuffer,
+    leaf_page_num: Option<PageNumber>,
+    pages_read: u32,
+    cancel: Option<&'a AtomicBool>,
+    values: PhantomData<V>,
+}
+
+/// The position of a query's iterator, from which `BTree::resume` can pick up the query where it left off. It refers
+/// to the page by number, so it remains valid after the page has been evicted from the cache.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Cursor {
+    page_num: PageNumber,
+    key_index: Option<usize>,
+    last_yielded_date: Option<Date>,
+    periods: u32,
+}
+
+enum QueryResultIteratorState<V> {
+    Continue,
+    YieldResult(Option<QueryResult<V>>),
+}
+
+impl<V: ValueCodec> Iterator for QueryResultIterator<'_, V> {
+    type Item = std::io::Result<QueryResult<V>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut state = Ok(QueryResultIteratorState::Continue);
+
+        while let Ok(QueryResultIteratorState::Continue) = state {
+            state = self.iterate()
+        }
+
+        match state {
+            Ok(QueryResultIteratorState::YieldResult(Some(result))) => {
+                if self.last_yielded_date != Some(result.key.date) {
+                    if self.query.max_periods == Some(self.periods) {
+                        trace_iterate!(
+                            "Stopping at {:?}, after {} dates",
+                            result.key,
+                            self.periods
+                        );
+                        return None;
+                    }
+                    self.periods += 1;
+                }
+                self.last_yielded_date = Some(result.key.date);
+                Some(Ok(result))
+            }
+            Ok(QueryResultIteratorState::YieldResult(None)) => None,
+            Err(e) => Some(Err(e)),
+            _ => None,
+        }
+    }
+}
+
+impl<'a, V: ValueCodec> QueryResultIterator<'a, V> {
+    fn new(
+        page_cache: &'a mut PageCache,
+        value_width: usize,
+        timestamp_width: usize,
+        query: Query,
+        page_num: u32,
+        key_index: Option<usize>,
+    ) -> QueryResultIterator<'a, V> {
+        QueryResultIterator {
+            page_cache,
+            page_num,
+            key_index,
+            query,
+            last_yielded_date: None,
+            periods: 0,
+            leaf_buf: PageBuffer {
+                buf: Vec::new(),
+                value_width,
+                timestamp_width,
+            },
+            leaf_page_num: None,
+            pages_read: 0,
+            cancel: None,
+            values: PhantomData,
+        }
+    }
+
+    /// Has the iterator check `cancel` before it moves on to each leaf after the first, failing with an `Interrupted`
+    /// error instead once it's set. The results returned before then are still valid, and the iterator's checkpoint
+    /// can resume the query from where it stopped.
+    pub fn with_cancel(mut self, cancel: &'a AtomicBool) -> QueryResultIterator<'a, V> {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// Copies the leaf at `page_num` into the leaf buffer, unless it's already there.
+    fn load_leaf(&mut self) -> std::io::Result<()> {
+        if self.leaf_page_num != Some(self.page_num) {
+            let page = self.page_cache.load(self.page_num as usize)?;
+            self.leaf_buf.buf.clear();
+            self.leaf_buf.buf.extend_from_slice(page);
+            self.leaf_page_num = Some(self.page_num);
+            self.pages_read += 1;
+        }
+        Ok(())
+    }
+
+    /// Collects the remaining results, up to `window` of them, and returns them from the earliest date to the latest.
+    /// Fails if there are more than `window` results, in which case the caller can raise the limit or iterate from
+    /// the latest date instead.
+    pub fn ascending(
+        self,
+        window: usize,
+    ) -> std::io::Result<std::iter::Rev<std::vec::IntoIter<QueryResult<V>>>> {
+        let mut results = Vec::new();
+        for result in self {
+            if results.len() == window {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Query has more than {} results", window),
+                ));
+            }
+            results.push(result?);
+        }
+        Ok(results.into_iter().rev())
+    }
+
+    /// Returns a cursor for the iterator's current position.
+    pub fn checkpoint(&self) -> Cursor {
+        Cursor {
+            page_num: self.page_num,
+            key_index: self.key_index,
+            last_yielded_date: self.last_yielded_date,
+            periods: self.periods,
+        }
+    }
+
+    fn iterate(&mut self) -> std::io::Result<QueryResultIteratorState<V>> {
+        self.load_leaf()?;
+        let leaf = LeafPage::<V>::new(&self.leaf_buf);
+        match node::step(&leaf, &mut self.key_index, Direction::Descending) {
+            Step::End => {
+                trace_iterate!("Stopping at the start of the first leaf {}", self.page_num);
+                Ok(QueryResultIteratorState::YieldResult(None))
+            }
+            Step::Sibling(_)
+                if self
+                    .cancel
+                    .is_some_and(|cancel| cancel.load(AtomicOrdering::Relaxed)) =>
+            {
+                trace_iterate!("Cancelled before leaving leaf {}", self.page_num);
+                Err(std::io::Error::new(
+                    ErrorKind::Interrupted,
+                    "Query was cancelled",
+                ))
+            }
+            Step::Sibling(previous_leaf) => {
+                trace_iterate!(
+                    "Moving from leaf {} to the previous leaf {}",
+                    self.page_num,
+                    previous_leaf
+                );
+                self.page_num = previous_leaf;
+                self.load_leaf()?;
+                self.key_index =
+                    node::first_index(&LeafPage::<V>::new(&self.leaf_buf), Direction::Descending);
+                Ok(QueryResultIteratorState::Continue)
+            }
+            Step::Entry(key, index) => {
+                if key.asset_id < self.query.asset_id
+                    || (key.asset_id == self.query.asset_id && key.date < self.query.start_date)
+                {
+                    trace_iterate!(
+                        "Stopping at {:?} in leaf {}, which is before asset {:?} or start date {:?}",
+                        key,
+                        self.page_num,
+                        self.query.asset_id,
+                        self.query.start_date
+                    );
+                    return Ok(QueryResultIteratorState::YieldResult(None));
+                }
+                let effective = match self.query.eff_timestamp {
+                    Some(eff_timestamp) => match leaf.page.effective_range(index) {
+                        Some(effective_range) => effective_range.contains(eff_timestamp),
+                        None => true,
+                    },
+                    None => true,
+                };
+                match self.last_yielded_date {
+                    _ if !effective => {
+                        trace_iterate!(
+                            "Skipping {:?}, which isn't effective at {:?}",
+                            key,
+                            self.query.eff_timestamp
+                        );
+                        Ok(QueryResultIteratorState::Continue)
+                    }
+                    None if key.asset_id > self.query.asset_id
+                        || key.date > self.query.end_date
+                        || self.query.excludes_timestamp(key.timestamp) =>
+                    {
+                        trace_iterate!("Skipping {:?}, which is outside the query", key);
+                        Ok(QueryResultIteratorState::Continue)
+                    }
+                    Some(d)
+                        if (d == key.date && self.query.one_row_per_date())
+                            || self.query.excludes_timestamp(key.timestamp) =>
+                    {
+                        trace_iterate!(
+                            "Skipping {:?}, with the last yielded date {:?}",
+                            key,
+                            self.last_yielded_date
+                        );
+                        Ok(QueryResultIteratorState::Continue)
+                    }
+                    _ => {
+                        trace_iterate!(
+                            "Yielding {:?}, with the last yielded date {:?}",
+                            key,
+                            self.last_yielded_date
+                        );
+                        Ok(QueryResultIteratorState::YieldResult(Some(QueryResult {
+                            id: self.query.id,
+                            key,
+                            value: leaf.value_at(index),
+                        })))
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    let (int_bytes, _) = buf.split_at(U32_SIZE);
+    u32::from_be_bytes(int_bytes.try_into().unwrap())
+}
+
+fn write_u32(buf: &mut [u8], source: u32) {
+    buf[0..U32_SIZE].copy_from_slice(&source.to_be_bytes()[..])
+}
+
+fn read_u64(buf: &[u8]) -> u64 {
+    let (int_bytes, _) = buf.split_at(U64_SIZE);
+    u64::from_be_bytes(int_bytes.try_into().unwrap())
+}
+
+fn write_u64(buf: &mut [u8], source: u64) {
+    buf[0..U64_SIZE].copy_from_slice(&source.to_be_bytes()[..])
+}
+
+fn read_f32(buf: &[u8]) -> f32 {
+    let (float_bytes, _) = buf.split_at(size_of::<f32>());
+    f32::from_be_bytes(float_bytes.try_into().unwrap())
+}
+
+fn write_f32(buf: &mut [u8], source: f32) {
+    buf[0..size_of::<f32>()].copy_from_slice(&source.to_be_bytes()[..])
+}
+
+/// The rows read from a CSV file, each of which fails separately if its line can't be read or parsed.
+pub type CsvRows = Box<dyn Iterator<Item = std::io::Result<(Key, Value)>>>;
+
+/// How the fields of a CSV file are separated and quoted. Within a quoted field, a doubled quote stands for a quote.
+#[derive(Clone, Copy, Debug)]
+pub struct CsvFormat {
+    pub delimiter: u8,
+    pub quote: u8,
+    /// Whether dates are written with separators, as `2020-01-31` or `2020/01/31`, rather than packed as `20200131`.
+    pub separated_dates: bool,
+}
+
+impl Default for CsvFormat {
+    fn default() -> CsvFormat {
+        CsvFormat {
+            delimiter: b',',
+            quote: b'"',
+            separated_dates: false,
+        }
+    }
+}
+
+/// Reads `asset_id,date,timestamp,value` rows from a CSV file. With the `gzip` feature, a file whose name ends in
+/// `.gz` is decompressed as it's read.
+pub fn read_csv(file_name: &str) -> std::io::Result<CsvRows> {
+    read_csv_with_format(file_name, CsvFormat::default())
+}
+
+/// Reads rows from a CSV file as `read_csv` does, in the given format.
+pub fn read_csv_with_format(file_name: &str, format: CsvFormat) -> std::io::Result<CsvRows> {
+    let file = File::open(file_name)?;
+    #[cfg(feature = "gzip")]
+    {
+        if file_name.ends_with(".gz") {
+            return Ok(read_csv_from_with_format(
+                flate2::read::GzDecoder::new(file),
+                format,
+            ));
+        }
+    }
+    Ok(read_csv_from_with_format(file, format))
+}
+
+/// Reads `asset_id,date,timestamp,value` rows from any source of CSV text. Any columns after the value are ignored. A
+/// line that isn't valid UTF-8 or doesn't parse fails with an `InvalidData` error giving its line number and contents.
+pub fn read_csv_from<R: Read + 'static>(source: R) -> CsvRows {
+    read_csv_from_with_format(source, CsvFormat::default())
+}
+
+/// Reads rows from any source of CSV text as `read_csv_from` does, in the given format.
+pub fn read_csv_from_with_format<R: Read + 'static>(source: R, format: CsvFormat) -> CsvRows {
+    // Only a newline ends a line, so that the reader's line numbers count a CRLF once. The carriage return is left at
+    // the end of the last field, and dropped from it below.
+    let reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .terminator(csv::Terminator::Any(b'\n'))
+        .delimiter(format.delimiter)
+        .quote(format.quote)
+        .from_reader(source);
+
+    Box::new(reader.into_byte_records().map(move |record| {
+        let record = record?;
+        let line_number = record.position().map_or(0, |position| position.line()) as usize;
+        let mut fields: Vec<&[u8]> = record.iter().collect();
+        if let Some(last) = fields.last_mut() {
+            if last.last() == Some(&b'\r') {
+                *last = &last[..last.len() - 1];
+            }
+        }
+        let line = fields.join(&format.delimiter);
+        let line = std::str::from_utf8(&line).map_err(|e| {
+            std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Line {} isn't valid UTF-8 ({}): {}",
+                    line_number,
+                    e,
+                    line.escape_ascii()
+                ),
+            )
+        })?;
+
+        let mut columns = fields.iter().map(|column| std::str::from_utf8(column).ok());
+        let mut next_column = || columns.next().flatten();
+        let asset_id = parse_column(next_column(), "asset_id", line_number, line)?;
+        let date = if format.separated_dates {
+            parse_column_with(
+                next_column(),
+                Date::parse_separated,
+                "date",
+                line_number,
+                line,
+            )?
+            .0
+        } else {
+            parse_column(next_column(), "date", line_number, line)?
+        };
+        let timestamp = parse_column(next_column(), "timestamp", line_number, line)?;
+        let value = parse_column(next_column(), "value", line_number, line)?;
+        Ok((
+            Key::new(AssetId(asset_id), Date(date), Timestamp(timestamp)),
+            value,
+        ))
+    }))
+}
+
+fn parse_column<T: FromStr>(
+    column: Option<&str>,
+    name: &str,
+    line_number: usize,
+    line: &str,
+) -> std::io::Result<T> {
+    parse_column_with(
+        column,
+        |column| T::from_str(column).ok(),
+        name,
+        line_number,
+        line,
+    )
+}
+
+fn parse_column_with<T>(
+    column: Option<&str>,
+    parse: impl Fn(&str) -> Option<T>,
+    name: &str,
+    line_number: usize,
+    line: &str,
+) -> std::io::Result<T> {
+    column.and_then(parse).ok_or_else(|| {
+        std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Line {} has no valid {}: {}", line_number, name, line),
+        )
+    })
+}
+
+#[cfg(test)]
+#[allow(clippy::while_let_on_iterator)]
+mod tests {
+    use crate::btree::file::{
+        bitemporal_page_size_for_keys, check_page_size, effective_ranges, page_size_for_keys,
+        page_size_for_keys_of, read_csv, read_csv_with_format, update_header, AssetId, BTree,
+        CsvFormat, Date, DictionaryCode, EffectiveRange, FileHeader, FileHeaderBuffer, Key,
+        MutPage, Page, PageBuffer, PageHeader, Query, Timestamp, TimestampBound, TreeWriter, Value,
+        ValueCodec, DELTA_HEADER_SIZE, FILE_HEADER_SIZE, FORMAT_VERSION, INNER_TYPE, KEY_SIZE,
+        KEY_VALUE_SIZE, LEAF_TYPE, PAGE_HEADER_SIZE, PAGE_TYPE_FIELD, U32_SIZE,
+        V3_FILE_HEADER_SIZE,
+    };
+    use crate::date::Calendar;
+    use std::cmp::min;
+    use std::fs;
+    use std::fs::{File, OpenOptions};
+    use std::io::{ErrorKind, Seek, SeekFrom, Write};
+    use std::path::Path;
+    use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+
+    fn remove_test_file(path: &str) {
+        if let Ok(()) = fs::remove_file(path) {
+            println!("Removed test file {}", path)
+        }
+    }
+
+    fn key(asset_id: u32, date: u32, timestamp: u64) -> Key {
+        Key::new(AssetId(asset_id), Date(date), Timestamp(timestamp))
+    }
+
+    fn query(asset_id: u32, start_date: u32, end_date: u32, timestamp: u64) -> Query {
+        Query::new(
+            0,
+            AssetId(asset_id),
+            Date(start_date),
+            Date(end_date),
+            Timestamp(timestamp),
+        )
+    }
+
+    fn small_inputs() -> Vec<(Key, Value)> {
+        vec![
+            (key(0, 20200131, 0), 1.0),
+            (key(0, 20200131, 10), 2.0),
+            (key(0, 20200131, 20), 3.0),
+            (key(0, 20200229, 5), 11.0),
+            (key(0, 20200229, 15), 12.0),
+            (key(0, 20200229, 25), 13.0),
+            (key(0, 20200331, 10), 110.0),
+            (key(0, 20200331, 20), 120.0),
+            (key(0, 20200331, 25), 130.0),
+            (key(1, 20200229, 5), 21.0),
+            (key(1, 20200229, 15), 22.0),
+            (key(1, 20200229, 25), 23.0),
+            (key(1, 20200331, 10), 220.0),
+            (key(1, 20200331, 20), 220.0),
+            (key(1, 20200331, 25), 230.0),
+            (key(1, 20200430, 10), 2100.0),
+            (key(1, 20200430, 20), 2200.0),
+            (key(1, 20200430, 25), 2300.0),
+        ]
+    }
+
+    /// Writes the rows of `small_inputs` to a file with three keys per page.
+    fn write_small(path: &str) {
+        remove_test_file(path);
+        let mut iter = small_inputs().into_iter();
+        let page_size = page_size_for_keys(3);
+        BTree::write_from_iterator(path, page_size as u32, &mut iter).unwrap();
+    }
+
+    #[test]
+    fn test_small() {
+        let path = "test_small.db";
+        write_small(path);
+
+        let file = File::open(path).unwrap();
+        let mut btree = BTree::from_file(file, 10).unwrap();
+        btree.print().unwrap();
+
+        check_query(&mut btree, query(0, 20200131, 20200131, 20), &[3.0], 1);
+        check_query(&mut btree, query(0, 20200131, 20200131, 15), &[2.0], 1);
+        check_query(
+            &mut btree,
+            query(0, 20200115, 20200405, 20),
+            &[120.0, 12.0, 3.0],
+            3,
+        );
+        check_query(
+            &mut btree,
+            query(1, 20200315, 20200515, 21),
+            &[2200.0, 220.0],
+            2,
+        );
+    }
+
+    #[test]
+    fn test_single_leaf() {
+        let path = "test_single_leaf.db";
+        remove_test_file(path);
+        let inputs = vec![
+            (key(0, 20200131, 10), 1.0),
+            (key(0, 20200229, 10), 2.0),
+            (key(1, 20200131, 10), 3.0),
+        ];
+        let page_size = page_size_for_keys(4) as u32;
+        BTree::write_from_iterator(path, page_size, &mut inputs.into_iter()).unwrap();
+
+        // With fewer keys than fit in a leaf, the root is the only page and there are no inner pages to descend.
+        let mut btree = BTree::from_file(File::open(path).unwrap(), 10).unwrap();
+        assert_eq!(1, btree.file_header.page_count);
+        assert_eq!(0, btree.file_header.root_page_num);
+        assert!(btree.load_page(0).unwrap().is_leaf());
+        assert_eq!(Ok(()), btree.fsck());
+
+        check_query(&mut btree, query(0, 20200101, 20201231, 10), &[2.0, 1.0], 1);
+        let mut values = |asset_id, start_date, end_date, timestamp| {
+            query_values(&mut btree, query(asset_id, start_date, end_date, timestamp))
+        };
+        assert_eq!(vec![2.0, 1.0], values(0, 20200101, 20201231, 10));
+        assert_eq!(vec![2.0], values(0, 20200201, 20200229, 10));
+        assert_eq!(vec![3.0], values(1, 20200101, 20201231, 10));
+        assert!(values(0, 20200101, 20200130, 10).is_empty());
+        assert!(values(1, 20200201, 20201231, 10).is_empty());
+        assert!(values(2, 20200101, 20201231, 10).is_empty());
+        assert!(values(0, 20200101, 20201231, 9).is_empty());
+
+        let iterator = btree.query(query(0, 20200101, 20201231, 10)).unwrap();
+        let values: Vec<Value> = iterator
+            .ascending(2)
+            .unwrap()
+            .map(|result| result.value)
+            .collect();
+        assert_eq!(vec![1.0, 2.0], values);
+        remove_test_file(path);
+    }
+
+    #[test]
+    fn test_query_nearest() {
+        let path = "test_query_nearest.db";
+        write_small(path);
+
+        let mut btree = BTree::from_file(File::open(path).unwrap(), 10).unwrap();
+        let mut nearest = |date, timestamp, max_back| {
+            btree
+                .query_nearest(
+                    AssetId(0),
+                    Date(date),
+                    Timestamp(timestamp),
+                    max_back,
+                    &Calendar::AllDays,
+                )
+                .unwrap()
+        };
+        assert_eq!(Some((Date(20200131), 3.0, 0)), nearest(20200131, 20, 0));
+        assert_eq!(Some((Date(20200131), 3.0, 2)), nearest(20200202, 20, 2));
+        assert_eq!(Some((Date(20200131), 2.0, 2)), nearest(20200202, 15, 5));
+        assert_eq!(None, nearest(20200202, 20, 1));
+        assert_eq!(None, nearest(20200130, 20, 30));
+        assert_eq!(Some((Date(20200131), 3.0, 28)), nearest(20200228, 20, 28));
+        assert_eq!(None, nearest(20200228, 20, 27));
+        assert_eq!(Some((Date(20200229), 12.0, 0)), nearest(20200229, 20, 28));
+        remove_test_file(path);
+    }
+
+    #[test]
+    fn test_trading_calendar() {
+        let path = "test_trading_calendar.db";
+        remove_test_file(path);
+        // Friday, Saturday, Monday and Wednesday, with a stray row on the Saturday.
+        let inputs = vec![
+            (key(0, 20200131, 10), 1.0),
+            (key(0, 20200201, 10), 9.0),
+            (key(0, 20200203, 10), 2.0),
+            (key(0, 20200205, 10), 3.0),
+        ];
+        BTree::write_from_iterator(path, page_size_for_keys(3) as u32, &mut inputs.into_iter())
+            .unwrap();
+        let mut btree = BTree::from_file(File::open(path).unwrap(), 10).unwrap();
+        let weekdays = Calendar::default();
+        let all_days = Calendar::AllDays;
+        let holiday = Calendar::Weekdays(vec![Date(20200203)].into_iter().collect());
+        let mut forward_fill = |start_date, calendar| {
+            btree
+                .forward_fill(
+                    AssetId(0),
+                    Date(start_date),
+                    Date(20200205),
+                    Timestamp(10),
+                    calendar,
+                )
+                .unwrap()
+                .into_iter()
+                .map(|(date, value)| (date.0, value))
+                .collect::<Vec<_>>()
+        };
+
+        // The weekend isn't filled in, and the Saturday row isn't carried into Monday.
+        assert_eq!(
+            vec![
+                (20200131, 1.0),
+                (20200203, 2.0),
+                (20200204, 2.0),
+                (20200205, 3.0)
+            ],
+            forward_fill(20200130, &weekdays)
+        );
+        assert_eq!(
+            vec![(20200131, 1.0), (20200204, 1.0), (20200205, 3.0)],
+            forward_fill(20200130, &holiday)
+        );
+        assert_eq!(
+            vec![
+                (20200131, 1.0),
+                (20200201, 9.0),
+                (20200202, 9.0),
+                (20200203, 2.0),
+                (20200204, 2.0),
+                (20200205, 3.0)
+            ],
+            forward_fill(20200130, &all_days)
+        );
+        assert_eq!(
+            vec![(20200204, 2.0), (20200205, 3.0)],
+            forward_fill(20200204, &weekdays)
+        );
+
+        // Going back one open day from Tuesday reaches Monday, or the Friday when Monday is a holiday, passing over
+        // the weekend without counting it.
+        let mut nearest = |date, max_back, calendar| {
+            btree
+                .query_nearest(AssetId(0), Date(date), Timestamp(10), max_back, calendar)
+                .unwrap()
+        };
+        assert_eq!(
+            Some((Date(20200203), 2.0, 1)),
+            nearest(20200204, 1, &weekdays)
+        );
+        assert_eq!(
+            Some((Date(20200131), 1.0, 1)),
+            nearest(20200204, 1, &holiday)
+        );
+        assert_eq!(None, nearest(20200204, 0, &holiday));
+        assert_eq!(
+            Some((Date(20200131), 1.0, 0)),
+            nearest(20200202, 0, &weekdays)
+        );
+        assert_eq!(
+            Some((Date(20200201), 9.0, 1)),
+            nearest(20200202, 1, &all_days)
+        );
+
+        // Without a limit, the open days are only counted back as far as the row that's found, and when there's no
+        // row there's nothing to count.
+        let days_back = weekdays.open_days(Date(20200206), Date(20991231)).len() as u32;
+        assert_eq!(
+            Some((Date(20200205), 3.0, days_back)),
+            nearest(20991231, u32::MAX, &weekdays)
+        );
+        assert_eq!(None, nearest(20200130, u32::MAX, &all_days));
+        remove_test_file(path);
+    }
+
+    #[test]
+    fn test_result_order() {
+        let path = "test_result_order.db";
+        remove_test_file(path);
+
+        let inputs = vec![
+            (key(0, 20200101, 10), 1.0),
+            (key(0, 20200102, 10), 2.0),
+            (key(0, 20200102, 20), 3.0),
+            (key(0, 20200103, 10), 4.0),
+            (key(1, 20200102, 10), 5.0),
+            (key(1, 20200103, 10), 6.0),
+            (key(1, 20200103, 20), 7.0),
+            (key(1, 20200104, 10), 8.0),
+            (key(2, 20200101, 10), 9.0),
+        ];
+        let page_size = page_size_for_keys(2) as u32;
+        BTree::write_from_iterator(path, page_size, &mut inputs.into_iter()).unwrap();
+        let mut btree = BTree::from_file(File::open(path).unwrap(), 4).unwrap();
+
+        let keys = |btree: &mut BTree, query: Query| {
+            let mut iterator = btree.query(query).unwrap();
+            let mut keys = Vec::new();
+            while let Some(result) = iterator.next() {
+                keys.push(result.unwrap().key);
+            }
+            keys
+        };
+        assert_eq!(
+            vec![
+                key(0, 20200103, 10),
+                key(0, 20200102, 20),
+                key(0, 20200101, 10)
+            ],
+            keys(&mut btree, query(0, 20200101, 20200104, 20))
+        );
+        assert_eq!(
+            vec![
+                key(1, 20200104, 10),
+                key(1, 20200103, 20),
+                key(1, 20200102, 10)
+            ],
+            keys(&mut btree, query(1, 20200101, 20200104, 20))
+        );
+        let range_query = Query {
+            timestamp_range: Some((Timestamp(0), Timestamp(20))),
+            ..query(1, 20200101, 20200103, 20)
+        };
+        assert_eq!(
+            vec![
+                key(1, 20200103, 20),
+                key(1, 20200103, 10),
+                key(1, 20200102, 10)
+            ],
+            keys(&mut btree, range_query)
+        );
+
+        let points = [
+            (AssetId(2), Date(20200101)),
+            (AssetId(0), Date(20200103)),
+            (AssetId(1), Date(20200103)),
+        ];
+        let results: Vec<Key> = btree
+            .query_points(&points, Timestamp(20))
+            .unwrap()
+            .into_iter()
+            .map(|result| result.unwrap().key)
+            .collect();
+        assert_eq!(
+            vec![
+                key(2, 20200101, 10),
+                key(0, 20200103, 10),
+                key(1, 20200103, 20)
+            ],
+            results
+        );
+        remove_test_file(path);
+    }
+
+    #[test]
+    fn test_latest_known() {
+        let path = "test_latest_known.db";
+        remove_test_file(path);
+
+        // The second of January has a correction dated well after the query's timestamp.
+        let inputs = vec![
+            (key(0, 20200101, 10), 1.0),
+            (key(0, 20200101, 20), 2.0),
+            (key(0, 20200102, 10), 3.0),
+            (key(0, 20200102, u64::MAX - 1), 4.0),
+            (key(0, 20200103, 5), 5.0),
+        ];
+        let page_size = page_size_for_keys(2) as u32;
+        BTree::write_from_iterator(path, page_size, &mut inputs.into_iter()).unwrap();
+        let mut btree = BTree::from_file(File::open(path).unwrap(), 4).unwrap();
+
+        check_query(
+            &mut btree,
+            query(0, 20200101, 20200103, 15),
+            &[5.0, 3.0, 1.0],
+            3,
+        );
+        let latest_known = |timestamp| Query {
+            latest_known: true,
+            ..query(0, 20200101, 20200103, timestamp)
+        };
+        check_query(&mut btree, latest_known(15), &[5.0, 4.0, 2.0], 3);
+        check_query(&mut btree, latest_known(0), &[5.0, 4.0, 2.0], 3);
+        let overridden = Query {
+            timestamp_bound: TimestampBound::Exclusive,
+            timestamp_range: Some((Timestamp(0), Timestamp(10))),
+            ..latest_known(0)
+        };
+        check_query(&mut btree, overridden, &[5.0, 4.0, 2.0], 3);
+        remove_test_file(path);
+    }
+
+    #[test]
+    fn test_check_page_size() {
+        let path = "test_check_page_size.db";
+        remove_test_file(path);
+
+        let min_page_size = page_size_for_keys(1);
+        for strict in [false, true] {
+            let error = check_page_size(min_page_size as u32 - 1, min_page_size, 4096, strict);
+            assert_eq!(ErrorKind::InvalidInput, error.unwrap_err().kind());
+            assert!(check_page_size(4096, min_page_size, 4096, strict).is_ok());
+            assert!(check_page_size(8192, min_page_size, 4096, strict).is_ok());
+        }
+        assert!(check_page_size(1000, min_page_size, 4096, false).is_ok());
+        #[cfg(feature = "log")]
+        {
+            capture_log::start();
+            assert!(check_page_size(1000, min_page_size, 4096, false).is_ok());
+            let warning = "Page size 1000 isn't a multiple of the block size 4096".to_string();
+            assert_eq!(Some(&warning), capture_log::messages().last());
+        }
+        let error = check_page_size(1000, min_page_size, 4096, true);
+        assert_eq!(ErrorKind::InvalidInput, error.unwrap_err().kind());
+
+        // The writers reject a page that can't hold a single entry, rather than writing an unreadable file.
+        let page_size = page_size_for_keys(1) as u32 - 1;
+        let error = BTree::write_from_iterator(path, page_size, &mut small_inputs().into_iter());
+        assert_eq!(ErrorKind::InvalidInput, error.unwrap_err().kind());
+        let page_size = page_size_for_keys(1) as u32 + DELTA_HEADER_SIZE as u32 - 1;
+        let error = BTree::write_delta_encoded_from_iterator(
+            path,
+            page_size,
+            &mut small_inputs().into_iter(),
+        );
+        assert_eq!(ErrorKind::InvalidInput, error.unwrap_err().kind());
+        assert!(!Path::new(path).exists());
+    }
+
+    #[test]
+    fn test_write_to_vec() {
+        let path = "test_write_to_vec.db";
+        write_small(path);
+
+        let page_size = page_size_for_keys(3) as u32;
+        let bytes = BTree::write_to_vec(page_size, &mut small_inputs().into_iter()).unwrap();
+        assert_eq!(fs::read(path).unwrap(), bytes);
+
+        let mut btree = BTree::from_reader(std::io::Cursor::new(bytes), 10).unwrap();
+        assert_eq!(Ok(()), btree.fsck());
+        check_query(
+            &mut btree,
+            query(0, 20200115, 20200405, 20),
+            &[120.0, 12.0, 3.0],
+            3,
+        );
+        check_query(
+            &mut btree,
+            query(1, 20200315, 20200515, 21),
+            &[2200.0, 220.0],
+            2,
+        );
+        remove_test_file(path);
+    }
+
+    #[test]
+    fn test_write_to() {
+        let path = "test_write_to.db";
+        write_small(path);
+
+        let page_size = page_size_for_keys(3) as u32;
+        let out = std::io::Cursor::new(Vec::new());
+        let out = BTree::write_to(out, page_size, &mut small_inputs().into_iter()).unwrap();
+        assert_eq!(fs::read(path).unwrap(), *out.get_ref());
+
+        let mut btree = BTree::from_reader(out, 10).unwrap();
+        check_query(&mut btree, query(0, 20200131, 20200131, 15), &[2.0], 1);
+        remove_test_file(path);
+    }
+
+    #[test]
+    fn test_contains() {
+        let path = "test_contains.db";
+        write_small(path);
+
+        let file = File::open(path).unwrap();
+        let mut btree = BTree::from_file(file, 10).unwrap();
+        for (key, _) in small_inputs() {
+            assert!(btree.contains(key.asset_id, key.date).unwrap(), "{:?}", key);
+        }
+        for (asset_id, date) in [
+            (0, 20200101),
+            (0, 20200215),
+            (0, 20200430),
+            (1, 20200131),
+            (1, 20200501),
+            (2, 20200229),
+        ] {
+            assert!(!btree.contains(AssetId(asset_id), Date(date)).unwrap());
+        }
+        remove_test_file(path);
+    }
+
+    #[test]
+    fn test_get() {
+        let path = "test_get.db";
+        write_small(path);
+
+        let file = File::open(path).unwrap();
+        let mut btree = BTree::from_file(file, 10).unwrap();
+        for (key, value) in small_inputs() {
+            let actual = btree.get(key.asset_id, key.date, key.timestamp).unwrap();
+            assert_eq!(Some(value), actual, "{:?}", key);
+        }
+        // An earlier timestamp on the date doesn't stand in for a missing one, as it would for get_latest.
+        assert_eq!(
+            Some(11.0),
+            btree
+                .get_latest(AssetId(0), Date(20200229), Timestamp(10))
+                .unwrap()
+                .map(|result| result.value)
+        );
+        for (asset_id, date, timestamp) in [
+            (0, 20200229, 10),
+            (0, 20200229, 4),
+            (0, 20200131, 30),
+            (0, 20200101, 0),
+            (1, 20200430, u64::MAX),
+            (2, 20200331, 10),
+        ] {
+            let actual = btree
+                .get(AssetId(asset_id), Date(date), Timestamp(timestamp))
+                .unwrap();
+            assert_eq!(None, actual, "{} {} {}", asset_id, date, timestamp);
+        }
+        remove_test_file(path);
+    }
+
+    #[test]
+    fn test_bitemporal() {
+        let path = "test_bitemporal.db";
+        remove_test_file(path);
+
+        let inputs = vec![
+            (key(0, 20200131, 10), 1.0, EffectiveRange::new(100, 199)),
+            (
+                key(0, 20200131, 10),
+                1.5,
+                EffectiveRange::new(200, u64::MAX),
+            ),
+            (
+                key(0, 20200229, 10),
+                2.0,
+                EffectiveRange::new(100, u64::MAX),
+            ),
+            (key(0, 20200331, 10), 3.0, EffectiveRange::new(150, 249)),
+            (key(0, 20200331, 10), 3.25, EffectiveRange::new(250, 299)),
+            (
+                key(0, 20200331, 10),
+                3.5,
+                EffectiveRange::new(300, u64::MAX),
+            ),
+        ];
+        let page_size = bitemporal_page_size_for_keys(2);
+        BTree::write_bitemporal_from_iterator(path, page_size as u32, &mut inputs.into_iter())
+            .unwrap();
+
+        let file = File::open(path).unwrap();
+        let mut btree = BTree::from_file(file, 10).unwrap();
+        assert!(btree.is_bitemporal());
+
+        let query = |eff_timestamp| Query {
+            eff_timestamp,
+            ..query(0, 20200101, 20201231, 10)
+        };
+        check_query(&mut btree, query(Some(120)), &[2.0, 1.0], 3);
+        check_query(&mut btree, query(Some(260)), &[3.25, 2.0, 1.5], 3);
+        check_query(&mut btree, query(Some(300)), &[3.5, 2.0, 1.5], 3);
+        check_query(&mut btree, query(None), &[3.5, 2.0, 1.5], 3);
+    }
+
+    #[test]
+    fn test_timestamp_bound() {
+        let path = "test_timestamp_bound.db";
+        remove_test_file(path);
+
+        let inputs = vec![
+            (key(0, 20200131, 5), 1.0),
+            (key(0, 20200131, 10), 1.5),
+            (key(0, 20200229, 10), 2.0),
+            (key(0, 20200331, 5), 3.0),
+        ];
+        let page_size = page_size_for_keys(3);
+        BTree::write_from_iterator(path, page_size as u32, &mut inputs.into_iter()).unwrap();
+
+        let file = File::open(path).unwrap();
+        let mut btree = BTree::from_file(file, 10).unwrap();
+        let mut values = |timestamp_bound| {
+            let mut iterator = btree
+                .query(Query {
+                    timestamp_bound,
+                    ..query(0, 20200101, 20201231, 10)
+                })
+                .unwrap();
+            let mut values = Vec::new();
+            while let Some(result) = iterator.next() {
+                values.push(result.unwrap().value);
+            }
+            values
+        };
+        assert_eq!(vec![3.0, 2.0, 1.5], values(TimestampBound::Inclusive));
+        assert_eq!(vec![3.0, 1.0], values(TimestampBound::Exclusive));
+        remove_test_file(path);
+    }
+
+    #[test]
+    fn test_write_from_unsorted_external() {
+        let path = "test_write_from_unsorted_external.db";
+        remove_test_file(path);
+
+        let mut inputs = small_inputs();
+        inputs.reverse();
+        inputs.push((key(0, 20200131, 20), 4.0));
+        let page_size = page_size_for_keys(3) as u32;
+        BTree::write_from_unsorted_external(path, page_size, &mut inputs.into_iter(), 4).unwrap();
+        for chunk in 0..5 {
+            assert!(fs::metadata(format!("{}.chunk{}", path, chunk)).is_err());
+        }
+
+        let file = File::open(path).unwrap();
+        let mut btree = BTree::from_file(file, 10).unwrap();
+        assert_eq!(Ok(()), btree.fsck());
+        check_query(&mut btree, query(0, 20200131, 20200131, 20), &[4.0], 1);
+        check_query(
+            &mut btree,
+            query(0, 20200115, 20200405, 20),
+            &[120.0, 12.0, 4.0],
+            3,
+        );
+        check_query(
+            &mut btree,
+            query(1, 20200315, 20200515, 21),
+            &[2200.0, 220.0],
+            3,
+        );
+
+        let error =
+            BTree::write_from_unsorted_external(path, page_size, &mut std::iter::empty(), 0);
+        assert_eq!(ErrorKind::InvalidInput, error.unwrap_err().kind());
+
+        // Once the first chunk has been spilled, it's swapped for a directory, which can be opened but not read, so
+        // the merge fails and the tree already at the path is left as it was.
+        let existing = fs::read(path).unwrap();
+        let first_chunk = format!("{}.chunk0", path);
+        let mut inputs = small_inputs().into_iter().enumerate().map(|(index, row)| {
+            if index == 4 {
+                fs::remove_file(&first_chunk).unwrap();
+                fs::create_dir(&first_chunk).unwrap();
+            }
+            row
+        });
+        let error = BTree::write_from_unsorted_external(path, page_size, &mut inputs, 4);
+        assert!(error.is_err());
+        assert_eq!(existing, fs::read(path).unwrap());
+        assert!(fs::metadata(format!("{}.tmp", path)).is_err());
+        fs::remove_dir(&first_chunk).unwrap();
+        remove_test_file(path);
+    }
+
+    #[test]
+    fn test_timestamp_range() {
+        let path = "test_timestamp_range.db";
+        write_small(path);
+
+        let file = File::open(path).unwrap();
+        let mut btree = BTree::from_file(file, 10).unwrap();
+        let mut values = |asset_id, timestamp_range| {
+            let mut iterator = btree
+                .query(Query {
+                    timestamp_range: Some(timestamp_range),
+                    ..query(asset_id, 20200101, 20200430, 0)
+                })
+                .unwrap();
+            let mut values = Vec::new();
+            while let Some(result) = iterator.next() {
+                values.push(result.unwrap().value);
+            }
+            values
+        };
+        assert_eq!(
+            vec![120.0, 110.0, 12.0, 3.0, 2.0],
+            values(0, (Timestamp(10), Timestamp(20)))
+        );
+        assert_eq!(
+            vec![2300.0, 2200.0, 230.0, 220.0, 23.0],
+            values(1, (Timestamp(20), Timestamp(25)))
+        );
+        assert_eq!(vec![1.0], values(0, (Timestamp(0), Timestamp(0))));
+        assert!(values(0, (Timestamp(21), Timestamp(24))).is_empty());
+        remove_test_file(path);
+    }
+
+    #[test]
+    fn test_effective_ranges() {
+        let path = "test_effective_ranges.db";
+        remove_test_file(path);
+
+        let published = vec![
+            (key(0, 20200131, 10), 1.0, 100),
+            (key(0, 20200131, 10), 1.5, 200),
+            (key(0, 20200229, 10), 2.0, 150),
+        ];
+        let rows: Vec<(Key, Value, EffectiveRange)> =
+            effective_ranges(published.into_iter()).collect();
+        assert_eq!(
+            vec![
+                (key(0, 20200131, 10), 1.0, EffectiveRange::new(100, 199)),
+                (
+                    key(0, 20200131, 10),
+                    1.5,
+                    EffectiveRange::new(200, u64::MAX)
+                ),
+                (
+                    key(0, 20200229, 10),
+                    2.0,
+                    EffectiveRange::new(150, u64::MAX)
+                ),
+            ],
+            rows
+        );
+
+        let page_size = bitemporal_page_size_for_keys(2);
+        BTree::write_bitemporal_from_iterator(path, page_size as u32, &mut rows.into_iter())
+            .unwrap();
+        let file = File::open(path).unwrap();
+        let mut btree = BTree::from_file(file, 10).unwrap();
+        let query = |eff_timestamp| Query {
+            eff_timestamp: Some(eff_timestamp),
+            ..query(0, 20200101, 20201231, 10)
+        };
+        check_query(&mut btree, query(120), &[1.0], 2);
+        check_query(&mut btree, query(199), &[2.0, 1.0], 2);
+        check_query(&mut btree, query(200), &[2.0, 1.5], 2);
+        remove_test_file(path);
+    }
+
+    fn check_query(btree: &mut BTree, query: Query, expected: &[f32], pages_read: u32) {
+        let mut iterator = btree.query(query).unwrap();
+
+        for expected_value in expected.iter() {
+            match iterator.next() {
+                Some(Ok(v)) => assert_eq!(v.value, *expected_value),
+                _ => panic!("Iterator ran out of elements"),
+            };
+        }
+
+        assert_eq!(iterator.pages_read, pages_read);
+    }
+
+    #[test]
+    fn test_no_page_cache() {
+        let path = "test_no_page_cache.db";
+        write_small(path);
+
+        let file = File::open(path).unwrap();
+        let mut btree = BTree::from_file(file, 0).unwrap();
+        check_query(
+            &mut btree,
+            query(0, 20200115, 20200405, 20),
+            &[120.0, 12.0, 3.0],
+            3,
+        );
+        check_query(
+            &mut btree,
+            query(1, 20200315, 20200515, 21),
+            &[2200.0, 220.0],
+            2,
+        );
+    }
+
+    #[test]
+    fn test_resize_cache() {
+        let path = "test_resize_cache.db";
+        write_small(path);
+
+        let file = File::open(path).unwrap();
+        let mut btree = BTree::from_file(file, 2).unwrap();
+        let check = |btree: &mut BTree| {
+            check_query(
+                btree,
+                query(0, 20200115, 20200405, 20),
+                &[120.0, 12.0, 3.0],
+                3,
+            );
+            check_query(btree, query(1, 20200315, 20200515, 21), &[2200.0, 220.0], 2);
+        };
+        check(&mut btree);
+        btree.resize_cache(10).unwrap();
+        check(&mut btree);
+        btree.resize_cache(1).unwrap();
+        check(&mut btree);
+        btree.resize_cache(0).unwrap();
+        check(&mut btree);
+        remove_test_file(path);
+    }
+
+    #[test]
+    fn test_warm() {
+        let path = "test_warm.db";
+        write_small(path);
+
+        let file = File::open(path).unwrap();
+        let mut btree = BTree::from_file(file, 10).unwrap();
+        btree.warm(AssetId(0), true).unwrap();
+        let warmed = btree.cache_stats();
+        assert_eq!(3, warmed.misses);
+
+        // The root, the inner page under it and the leaf were all loaded by warm.
+        check_query(&mut btree, query(0, 20200131, 20200131, 20), &[3.0], 1);
+        let queried = btree.cache_stats();
+        assert_eq!(warmed.misses, queried.misses);
+        assert!(queried.hits - warmed.hits >= 3);
+
+        let file = File::open(path).unwrap();
+        let mut uncached = BTree::from_file(file, 0).unwrap();
+        uncached.warm(AssetId(0), false).unwrap();
+        assert!(uncached.warm(AssetId(0), true).is_err());
+        remove_test_file(path);
+    }
+
+    #[test]
+    fn test_cancel() {
+        let path = "test_cancel.db";
+        write_small(path);
+
+        let file = File::open(path).unwrap();
+        let mut btree = BTree::from_file(file, 10).unwrap();
+        let cancel = AtomicBool::new(false);
+        let query = || query(0, 20200115, 20200405, 20);
+        let mut iterator = btree.query(query()).unwrap().with_cancel(&cancel);
+        assert_eq!(120.0, iterator.next().unwrap().unwrap().value);
+        cancel.store(true, AtomicOrdering::Relaxed);
+        let error = iterator.next().unwrap().unwrap_err();
+        assert_eq!(ErrorKind::Interrupted, error.kind());
+        assert_eq!(1, iterator.pages_read);
+
+        let cursor = iterator.checkpoint();
+        let mut iterator = btree.resume::<Value>(cursor, query()).unwrap();
+        assert_eq!(12.0, iterator.next().unwrap().unwrap().value);
+        assert_eq!(3.0, iterator.next().unwrap().unwrap().value);
+        assert!(iterator.next().is_none());
+        remove_test_file(path);
+    }
+
+    #[test]
+    fn test_pages_read_within_leaf() {
+        let path = "test_pages_read_within_leaf.db";
+        remove_test_file(path);
+        let page_size = page_size_for_keys(9);
+        BTree::write_from_iterator(path, page_size as u32, &mut small_inputs().into_iter())
+            .unwrap();
+
+        let file = File::open(path).unwrap();
+        let mut btree = BTree::from_file(file, 0).unwrap();
+        let mut iterator = btree.query(query(0, 20200101, 20201231, 25)).unwrap();
+        let mut values = Vec::new();
+        while let Some(result) = iterator.next() {
+            values.push(result.unwrap().value);
+            assert_eq!(1, iterator.pages_read);
+        }
+        assert_eq!(vec![130.0, 13.0, 3.0], values);
+        assert_eq!(1, iterator.pages_read);
+    }
+
+    #[test]
+    fn test_ascending() {
+        let path = "test_ascending.db";
+        write_small(path);
+
+        let file = File::open(path).unwrap();
+        let mut btree = BTree::from_file(file, 10).unwrap();
+        let iterator = btree.query(query(0, 20200115, 20200405, 20)).unwrap();
+        let values: Vec<Value> = iterator
+            .ascending(3)
+            .unwrap()
+            .map(|result| result.value)
+            .collect();
+        assert_eq!(vec![3.0, 12.0, 120.0], values);
+
+        let iterator = btree.query(query(0, 20200115, 20200405, 20)).unwrap();
+        let error = iterator.ascending(2).err().unwrap();
+        assert_eq!(ErrorKind::InvalidInput, error.kind());
+    }
+
+    /// Captures what's logged, for the tests that check it. A logger can only be set once, so it's shared by them.
+    #[cfg(feature = "log")]
+    mod capture_log {
+        use std::sync::{Mutex, Once};
+        use std::thread::{self, ThreadId};
+
+        /// Keeps the messages logged by each test thread, since the tests run in parallel.
+        struct CaptureLogger;
+        static MESSAGES: Mutex<Vec<(ThreadId, String)>> = Mutex::new(Vec::new());
+        static LOGGER: CaptureLogger = CaptureLogger;
+        static START: Once = Once::new();
+
+        impl log::Log for CaptureLogger {
+            fn enabled(&self, _metadata: &log::Metadata) -> bool {
+                true
+            }
+
+            fn log(&self, record: &log::Record) {
+                let message = (thread::current().id(), record.args().to_string());
+                MESSAGES.lock().unwrap().push(message);
+            }
+
+            fn flush(&self) {}
+        }
+
+        /// Starts capturing messages at every level, unless another test already has.
+        pub fn start() {
+            START.call_once(|| {
+                log::set_logger(&LOGGER).unwrap();
+                log::set_max_level(log::LevelFilter::Trace);
+            });
+        }
+
+        /// The messages logged by the current thread.
+        pub fn messages() -> Vec<String> {
+            MESSAGES
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(thread_id, _)| *thread_id == thread::current().id())
+                .map(|(_, message)| message.clone())
+                .collect()
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "trace")]
+    fn test_trace_asset_boundary() {
+        capture_log::start();
+        let path = "test_trace_asset_boundary.db";
+        write_small(path);
+        let file = File::open(path).unwrap();
+        let mut btree = BTree::from_file(file, 10).unwrap();
+        let mut iterator = btree.query(query(1, 20200101, 20200515, 21)).unwrap();
+        let mut values = Vec::new();
+        while let Some(result) = iterator.next() {
+            values.push(result.unwrap().value);
+        }
+        assert_eq!(vec![2200.0, 220.0, 22.0], values);
+
+        let messages = capture_log::messages();
+        let stop = format!(
+            "Stopping at {:?} in leaf 2, which is before asset {:?} or start date {:?}",
+            key(0, 20200331, 25),
+            AssetId(1),
+            Date(20200101)
+        );
+        assert_eq!(Some(&stop), messages.last(), "{:#?}", messages);
+    }
+
+    #[test]
+    fn test_max_periods() {
+        let path = "test_max_periods.db";
+        remove_test_file(path);
+        let inputs: Vec<(Key, Value)> = (1..=20)
+            .flat_map(|day| {
+                let date = 20200100 + day;
+                vec![
+                    (key(0, date, 10), day as Value),
+                    (key(0, date, 20), (day * 10) as Value),
+                ]
+            })
+            .chain((1..=5).map(|day| (key(1, 20200100 + day, 10), -1.0)))
+            .collect();
+        BTree::write_from_iterator(path, page_size_for_keys(4) as u32, &mut inputs.into_iter())
+            .unwrap();
+        let mut btree = BTree::from_file(File::open(path).unwrap(), 10).unwrap();
+
+        let max_periods = |max_periods| Query {
+            max_periods: Some(max_periods),
+            ..query(0, 20200106, 20200115, 15)
+        };
+        assert_eq!(
+            vec![15.0, 14.0, 13.0],
+            query_values(&mut btree, max_periods(3))
+        );
+        let all: Vec<Value> = (6..=15).rev().map(|day| day as Value).collect();
+        assert_eq!(all, query_values(&mut btree, max_periods(10)));
+        assert_eq!(all, query_values(&mut btree, max_periods(50)));
+        assert!(query_values(&mut btree, max_periods(0)).is_empty());
+
+        // The cap counts dates rather than rows, so every row of a date in a timestamp range comes back.
+        let range_query = Query {
+            timestamp_range: Some((Timestamp(0), Timestamp(20))),
+            ..max_periods(2)
+        };
+        assert_eq!(
+            vec![150.0, 15.0, 140.0, 14.0],
+            query_values(&mut btree, range_query)
+        );
+
+        // A resumed query carries on counting from where it was checkpointed.
+        let mut iterator = btree.query(max_periods(3)).unwrap();
+        assert_eq!(15.0, iterator.next().unwrap().unwrap().value);
+        assert_eq!(14.0, iterator.next().unwrap().unwrap().value);
+        let cursor = iterator.checkpoint();
+        let resumed: Vec<Value> = btree
+            .resume(cursor, max_periods(3))
+            .unwrap()
+            .map(|result| result.unwrap().value)
+            .collect();
+        assert_eq!(vec![13.0], resumed);
+        remove_test_file(path);
+    }
+
+    #[test]
+    fn test_checkpoint_and_resume() {
+        let path = "test_checkpoint_and_resume.db";
+        write_small(path);
+
+        let file = File::open(path).unwrap();
+        let mut btree = BTree::from_file(file, 1).unwrap();
+        let query = || query(0, 20200115, 20200405, 25);
+
+        let mut full_scan = Vec::new();
+        let mut iterator = btree.query(query()).unwrap();
+        while let Some(result) = iterator.next() {
+            full_scan.push(result.unwrap());
+        }
+        assert_eq!(3, full_scan.len());
+
+        let mut paged_scan = Vec::new();
+        let mut iterator = btree.query(query()).unwrap();
+        for _ in 0..2 {
+            paged_scan.push(iterator.next().unwrap().unwrap());
+        }
+        let cursor = iterator.checkpoint();
+
+        // Evict the iterator's page from the single page cache.
+        btree.print().unwrap();
+
+        // The file's values are f32s, so they can't be resumed as anything else.
+        let error = btree.resume::<f64>(cursor, query()).err().unwrap();
+        assert_eq!(ErrorKind::InvalidData, error.kind());
+
+        let mut iterator = btree.resume(cursor, query()).unwrap();
+        while let Some(result) = iterator.next() {
+            paged_scan.push(result.unwrap());
+        }
+        assert_eq!(full_scan, paged_scan);
+    }
+
+    #[test]
+    fn test_get_latest() {
+        let path = "test_get_latest.db";
+        write_small(path);
+
+        let file = File::open(path).unwrap();
+        let mut btree = BTree::from_file(file, 10).unwrap();
+        let mut get_latest = |asset_id, date, timestamp| {
+            btree
+                .get_latest(AssetId(asset_id), Date(date), Timestamp(timestamp))
+                .unwrap()
+                .map(|result| (result.key, result.value))
+        };
+
+        // Present, including rows at the start and end of a leaf.
+        assert_eq!(
+            Some((key(0, 20200131, 20), 3.0)),
+            get_latest(0, 20200131, 20)
+        );
+        assert_eq!(
+            Some((key(0, 20200131, 10), 2.0)),
+            get_latest(0, 20200131, 15)
+        );
+        assert_eq!(
+            Some((key(0, 20200229, 25), 13.0)),
+            get_latest(0, 20200229, 100)
+        );
+        assert_eq!(
+            Some((key(1, 20200229, 5), 21.0)),
+            get_latest(1, 20200229, 5)
+        );
+        assert_eq!(
+            Some((key(1, 20200430, 25), 2300.0)),
+            get_latest(1, 20200430, 30)
+        );
+
+        // No row at or before the timestamp.
+        assert_eq!(None, get_latest(0, 20200229, 4));
+        assert_eq!(None, get_latest(1, 20200331, 9));
+
+        // Absent keys.
+        assert_eq!(None, get_latest(0, 20200115, 100));
+        assert_eq!(None, get_latest(0, 20200430, 100));
+        assert_eq!(None, get_latest(2, 20200430, 100));
+    }
+
+    #[test]
+    fn test_query_points() {
+        let path = "test_query_points.db";
+        write_small(path);
+
+        let file = File::open(path).unwrap();
+        let mut btree = BTree::from_file(file, 10).unwrap();
+        let points = [
+            (AssetId(1), Date(20200430)),
+            (AssetId(0), Date(20200131)),
+            (AssetId(2), Date(20200131)),
+            (AssetId(0), Date(20200229)),
+            (AssetId(0), Date(20200215)),
+            (AssetId(1), Date(20200229)),
+            (AssetId(0), Date(20200131)),
+        ];
+        let results: Vec<Option<(usize, Key, Value)>> = btree
+            .query_points(&points, Timestamp(15))
+            .unwrap()
+            .into_iter()
+            .map(|result| result.map(|result| (result.id, result.key, result.value)))
+            .collect();
+        assert_eq!(
+            vec![
+                Some((0, key(1, 20200430, 10), 2100.0)),
+                Some((1, key(0, 20200131, 10), 2.0)),
+                None,
+                Some((3, key(0, 20200229, 15), 12.0)),
+                None,
+                Some((5, key(1, 20200229, 15), 22.0)),
+                Some((6, key(0, 20200131, 10), 2.0)),
+            ],
+            results
+        );
+        assert!(btree.query_points(&[], Timestamp(15)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_page_flags() {
+        let mut page = PageBuffer::new(page_size_for_keys(3) as u32, LEAF_TYPE, 4);
+        page.set_num_keys(2);
+        page.set_extra_page_num(7);
+        page.set_flag(0, true);
+        page.set_flag(5, true);
+        page.set_flag(31, true);
+        page.set_flag(5, false);
+
+        assert!(page.flag(0));
+        assert!(!page.flag(5));
+        assert!(page.flag(31));
+        assert_eq!(
+            PageHeader {
+                page_type: LEAF_TYPE,
+                num_keys: 2,
+                extra_page_num: 7,
+                flags: 1 | (1 << 31),
+            },
+            page.header()
+        );
+    }
+
+    #[test]
+    fn test_update_header() {
+        let path = "test_update_header.db";
+        write_small(path);
+        let original = fs::read(path).unwrap();
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .unwrap();
+        let header = FileHeaderBuffer::from_file(&mut file)
+            .unwrap()
+            .get()
+            .unwrap();
+        update_header(
+            &mut file,
+            FileHeader {
+                root_page_num: 0,
+                ..header
+            },
+        )
+        .unwrap();
+        assert_eq!(FILE_HEADER_SIZE as u64, file.stream_position().unwrap());
+
+        let mut file = File::open(path).unwrap();
+        let updated = FileHeaderBuffer::from_file(&mut file)
+            .unwrap()
+            .get()
+            .unwrap();
+        assert_eq!(
+            FileHeader {
+                format_version: FORMAT_VERSION,
+                page_size: page_size_for_keys(3) as u32,
+                page_count: header.page_count,
+                root_page_num: 0,
+                leaf_type: LEAF_TYPE,
+                value_codec: Value::ID,
+                value_width: Value::WIDTH as u32,
+                flags: 0,
+            },
+            updated
+        );
+        let rewritten = fs::read(path).unwrap();
+        assert_eq!(original[FILE_HEADER_SIZE..], rewritten[FILE_HEADER_SIZE..]);
+    }
+
+    /// Writes rows into a file in format version 2, with 32 bit timestamps, putting three rows in each leaf under a
+    /// single root.
+    fn write_v2(path: &str, inputs: &[(Key, Value)]) {
+        let page_size = (PAGE_HEADER_SIZE + 6 * 4 * U32_SIZE) as u32;
+        let new_page = |page_type| {
+            let mut page = PageBuffer {
+                buf: vec![0; page_size as usize],
+                value_width: Value::WIDTH,
+                timestamp_width: U32_SIZE,
+            };
+            page.set_header_field(PAGE_TYPE_FIELD, page_type);
+            page
+        };
+
+        let mut pages = Vec::new();
+        let mut root = new_page(INNER_TYPE);
+        for (leaf_num, rows) in inputs.chunks(3).enumerate() {
+            let mut leaf = new_page(LEAF_TYPE);
+            for (index, (key, value)) in rows.iter().enumerate() {
+                leaf.set_key(index, *key);
+                leaf.set_value(index, *value);
+            }
+            leaf.set_num_keys(rows.len() as u32);
+            leaf.set_extra_page_num(leaf_num.checked_sub(1).map_or(u32::MAX, |n| n as u32));
+            if leaf_num > 0 {
+                root.set_key(leaf_num - 1, rows[0].0);
+            }
+            root.set_page_number(leaf_num, leaf_num as u32);
+            pages.push(leaf);
+        }
+        root.set_num_keys(pages.len() as u32 - 1);
+        pages.push(root);
+
+        let mut header = FileHeaderBuffer::new();
+        header.set(FileHeader {
+            format_version: 2,
+            page_size,
+            page_count: pages.len() as u32,
+            root_page_num: pages.len() as u32 - 1,
+            leaf_type: LEAF_TYPE,
+            value_codec: Value::ID,
+            value_width: Value::WIDTH as u32,
+            flags: 0,
+        });
+        let mut contents = header.buf[..V3_FILE_HEADER_SIZE].to_vec();
+        for page in pages {
+            contents.extend(page.buf);
+        }
+        fs::write(path, contents).unwrap();
+    }
+
+    fn query_values(btree: &mut BTree, query: Query) -> Vec<Value> {
+        let mut iterator = btree.query(query).unwrap();
+        let mut values = Vec::new();
+        while let Some(result) = iterator.next() {
+            values.push(result.unwrap().value);
+        }
+        values
+    }
+
+    #[test]
+    fn test_migrate() {
+        let path = "test_migrate.db";
+        let v1_path = "test_migrate_v1.db";
+        let migrated_path = "test_migrate_migrated.db";
+        let queries = || {
+            vec![
+                query(0, 20200101, 20201231, 20),
+                query(0, 20200131, 20200131, 15),
+                query(1, 20200315, 20200515, 21),
+                query(1, 20200101, 20201231, 100),
+            ]
+        };
+
+        write_small(migrated_path);
+        let mut btree = BTree::from_file(File::open(migrated_path).unwrap(), 10).unwrap();
+        let expected: Vec<Vec<Value>> = queries()
+            .into_iter()
+            .map(|query| query_values(&mut btree, query))
+            .collect();
+
+        // The version 1 file holds the rows of small_inputs, three to a page, as the writer from before the format
+        // was versioned laid them out. That writer left an inner page started after another filled up with its first
+        // child pointing at page 0, so a query can miss a leaf, but the leaves still link back through every row,
+        // which is what migrating reads.
+        write_v2(path, &small_inputs());
+        fs::write(
+            v1_path,
+            &include_bytes!("../../tests/content/small_v1.bin")[..],
+        )
+        .unwrap();
+        for (old_path, format_version) in [(path, 2), (v1_path, 1)] {
+            let mut btree = BTree::from_file(File::open(old_path).unwrap(), 10).unwrap();
+            assert_eq!(format_version, btree.file_header.format_version);
+            assert_eq!(Value::ID, btree.file_header.value_codec);
+            check_query(
+                &mut btree,
+                query(1, 20200315, 20200515, 21),
+                &[2200.0, 220.0],
+                2,
+            );
+
+            // The keys are wider after migrating, so the rows are rewritten rather than copied.
+            BTree::migrate(old_path, migrated_path).unwrap();
+            let mut btree = BTree::from_file(File::open(migrated_path).unwrap(), 10).unwrap();
+            assert_eq!(FORMAT_VERSION, btree.file_header.format_version);
+            assert_eq!(Ok(()), btree.fsck());
+            for (query, expected) in queries().into_iter().zip(&expected) {
+                assert_eq!(*expected, query_values(&mut btree, query));
+            }
+        }
+
+        write_small(path);
+        let current = fs::read(path).unwrap();
+        BTree::migrate(path, migrated_path).unwrap();
+        assert_eq!(current, fs::read(migrated_path).unwrap());
+
+        // A version 3 file has the same pages, under a header without the flags.
+        let mut v3 = current[..V3_FILE_HEADER_SIZE].to_vec();
+        v3[U32_SIZE..2 * U32_SIZE].copy_from_slice(&3u32.to_be_bytes());
+        v3.extend_from_slice(&current[FILE_HEADER_SIZE..]);
+        fs::write(v1_path, v3).unwrap();
+        BTree::migrate(v1_path, migrated_path).unwrap();
+        assert_eq!(current, fs::read(migrated_path).unwrap());
+
+        let mut unsupported = current.clone();
+        unsupported[U32_SIZE..2 * U32_SIZE].copy_from_slice(&(FORMAT_VERSION + 1).to_be_bytes());
+        fs::write(path, unsupported).unwrap();
+        let error = BTree::from_file(File::open(path).unwrap(), 10)
+            .err()
+            .unwrap();
+        assert_eq!(ErrorKind::InvalidData, error.kind());
+
+        remove_test_file(path);
+        remove_test_file(v1_path);
+        remove_test_file(migrated_path);
+    }
+
+    /// Reads from a cursor, failing every read once the given number of them have been made.
+    struct FailingReader {
+        inner: std::io::Cursor<Vec<u8>>,
+        reads_left: usize,
+    }
+
+    impl std::io::Read for FailingReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.reads_left == 0 {
+                return Err(std::io::Error::other("Read failed"));
+            }
+            self.reads_left -= 1;
+            self.inner.read(buf)
+        }
+    }
+
+    impl Seek for FailingReader {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn test_rewrite_read_error() {
+        let path = "test_rewrite_read_error.db";
+        let dst_path = "test_rewrite_read_error_dst.db";
+        write_v2(path, &small_inputs());
+        write_small(dst_path);
+        let existing = fs::read(dst_path).unwrap();
+
+        // Each read that fails, whether while the leaves are listed or while their rows are written, leaves the
+        // destination as it was, until there are enough reads for the rewrite to succeed.
+        let mut reads = 0;
+        loop {
+            let reader = FailingReader {
+                inner: std::io::Cursor::new(fs::read(path).unwrap()),
+                reads_left: reads,
+            };
+            reads += 1;
+            let mut btree = match BTree::from_reader(reader, 1) {
+                Ok(btree) => btree,
+                Err(_) => continue,
+            };
+            match btree.rewrite::<Value>(dst_path) {
+                Ok(()) => break,
+                Err(_) => {
+                    assert_eq!(existing, fs::read(dst_path).unwrap(), "{} reads", reads);
+                    assert!(fs::metadata(format!("{}.tmp", dst_path)).is_err());
+                }
+            }
+        }
+        let mut btree = BTree::from_file(File::open(dst_path).unwrap(), 10).unwrap();
+        assert_eq!(Ok(()), btree.fsck());
+        check_query(
+            &mut btree,
+            query(1, 20200315, 20200515, 21),
+            &[2200.0, 220.0],
+            2,
+        );
+        remove_test_file(path);
+        remove_test_file(dst_path);
+    }
 
     #[test]
-    fn test_small() {
-        let path = "test_small.db";
-        match fs::remove_file(path) {
-            Ok(()) => println!("Removed test file {}", path),
-            _ => {}
-        }
+    fn test_millisecond_timestamps() {
+        let path = "test_millisecond_timestamps.db";
+        remove_test_file(path);
 
+        // Milliseconds since the epoch are past what 32 bits hold, and versions a millisecond apart stay distinct.
+        let published = 1_600_000_000_000;
         let inputs = vec![
-            (Key::new(0, 20200131, 0), 1.0),
-            (Key::new(0, 20200131, 10), 2.0),
-            (Key::new(0, 20200131, 20), 3.0),
-            (Key::new(0, 20200229, 5), 11.0),
-            (Key::new(0, 20200229, 15), 12.0),
-            (Key::new(0, 20200229, 25), 13.0),
-            (Key::new(0, 20200331, 10), 110.0),
-            (Key::new(0, 20200331, 20), 120.0),
-            (Key::new(0, 20200331, 25), 130.0),
-            (Key::new(1, 20200229, 5), 21.0),
-            (Key::new(1, 20200229, 15), 22.0),
-            (Key::new(1, 20200229, 25), 23.0),
-            (Key::new(1, 20200331, 10), 220.0),
-            (Key::new(1, 20200331, 20), 220.0),
-            (Key::new(1, 20200331, 25), 230.0),
-            (Key::new(1, 20200430, 10), 2100.0),
-            (Key::new(1, 20200430, 20), 2200.0),
-            (Key::new(1, 20200430, 25), 2300.0),
+            (key(0, 20200913, published), 1.0),
+            (key(0, 20200913, published + 1), 2.0),
+            (key(0, 20200913, published + 999), 3.0),
+            (key(0, 20200914, published + 86_400_000), 4.0),
         ];
-        let mut iter = inputs.into_iter();
+        let page_size = page_size_for_keys(3) as u32;
+        BTree::write_from_iterator(path, page_size, &mut inputs.into_iter()).unwrap();
+
+        let mut btree = BTree::from_file(File::open(path).unwrap(), 4).unwrap();
+        let date = Date(20200913);
+        for (timestamp, expected) in [
+            (published - 1, None),
+            (published, Some(1.0)),
+            (published + 1, Some(2.0)),
+            (published + 998, Some(2.0)),
+            (published + 999, Some(3.0)),
+        ] {
+            let result = btree
+                .get_latest(AssetId(0), date, Timestamp(timestamp))
+                .unwrap();
+            assert_eq!(expected, result.map(|result| result.value));
+        }
+        assert_eq!(
+            vec![4.0, 3.0],
+            query_values(&mut btree, query(0, 20200101, 20201231, u64::MAX))
+        );
+        remove_test_file(path);
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_read_gzip_csv() {
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+        use std::io::Write;
+
+        let path = "test_read_gzip_csv.csv";
+        let gz_path = "test_read_gzip_csv.csv.gz";
+        let csv: String = small_inputs()
+            .iter()
+            .map(|(key, value)| {
+                format!(
+                    "{},{},{},{}\n",
+                    key.asset_id.0, key.date.0, key.timestamp.0, value
+                )
+            })
+            .collect();
+        fs::write(path, &csv).unwrap();
+        let mut encoder = GzEncoder::new(File::create(gz_path).unwrap(), Compression::default());
+        encoder.write_all(csv.as_bytes()).unwrap();
+        encoder.finish().unwrap();
+
+        let plain: Vec<(Key, Value)> = read_csv(path).unwrap().map(Result::unwrap).collect();
+        let gzipped: Vec<(Key, Value)> = read_csv(gz_path).unwrap().map(Result::unwrap).collect();
+        assert_eq!(small_inputs(), plain);
+        assert_eq!(plain, gzipped);
+
+        remove_test_file(path);
+        remove_test_file(gz_path);
+    }
+
+    #[test]
+    #[cfg(feature = "compressed")]
+    fn test_compress() {
+        let path = "test_compress.db";
+        let compressed_path = "test_compress_compressed.db";
+        let inputs: Vec<(Key, Value)> = (0..3)
+            .flat_map(|asset_id| {
+                (1..=12).flat_map(move |month| {
+                    (1..=28).map(move |day| {
+                        let date = 20200000 + month * 100 + day;
+                        (key(asset_id, date, 1), (month * 100 + day) as Value)
+                    })
+                })
+            })
+            .collect();
+        BTree::write_from_iterator(path, 4096, &mut inputs.into_iter()).unwrap();
+        BTree::compress(path, compressed_path, 3).unwrap();
+        assert!(
+            fs::metadata(compressed_path).unwrap().len() < fs::metadata(path).unwrap().len() / 2
+        );
+
+        let queries = || {
+            vec![
+                query(0, 20200101, 20201231, 1),
+                query(1, 20200315, 20200515, 1),
+                query(2, 20201201, 20201231, 1),
+                query(3, 20200101, 20201231, 1),
+            ]
+        };
+        let mut btree = BTree::from_file(File::open(path).unwrap(), 4).unwrap();
+        let mut compressed = BTree::from_file(File::open(compressed_path).unwrap(), 4).unwrap();
+        assert!(compressed.file_header.is_compressed());
+        assert_eq!(Ok(()), compressed.fsck());
+        for (query, compressed_query) in queries().into_iter().zip(queries()) {
+            let expected = query_values(&mut btree, query);
+            assert_eq!(expected, query_values(&mut compressed, compressed_query));
+        }
+
+        let error = BTree::compress(compressed_path, path, 3).unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, error.kind());
+
+        remove_test_file(path);
+        remove_test_file(compressed_path);
+    }
+
+    #[test]
+    #[cfg(feature = "mmap")]
+    fn test_open_mmap() {
+        let path = "test_open_mmap.db";
+        remove_test_file(path);
+        let mut inputs = (0..3).flat_map(|asset_id| {
+            (1..=12).map(move |month| {
+                let date = 20200000 + month * 100 + 1;
+                (key(asset_id, date, 1), (asset_id * 100 + month) as Value)
+            })
+        });
+        BTree::write_from_iterator(path, page_size_for_keys(4) as u32, &mut inputs).unwrap();
+
+        let mut buffered = BTree::from_file(File::open(path).unwrap(), 4).unwrap();
+        let mut mapped = BTree::open_mmap(path).unwrap();
+        for asset_id in 0..4 {
+            let query = || query(asset_id, 20200201, 20201001, 1);
+            assert_eq!(
+                query_values(&mut buffered, query()),
+                query_values(&mut mapped, query())
+            );
+        }
+        assert_eq!(Ok(()), mapped.fsck());
+        assert_eq!(4 * page_size_for_keys(4), buffered.cache_bytes());
+        assert_eq!(0, mapped.cache_bytes());
+        assert!(mapped.resize_cache(4).is_err());
+        assert!(mapped.warm(AssetId(1), true).is_err());
+        assert_eq!(
+            ErrorKind::UnexpectedEof,
+            mapped.load_page(100).err().unwrap().kind()
+        );
+
+        // Migrating onto the mapped file's path renames a new file over it rather than writing to it, so the mapping
+        // still reads the pages it had.
+        BTree::migrate(path, path).unwrap();
+        assert_eq!(Ok(()), mapped.fsck());
+        let mut migrated = BTree::from_file(File::open(path).unwrap(), 4).unwrap();
+        assert_eq!(
+            query_values(&mut buffered, query(1, 20200101, 20201231, 1)),
+            query_values(&mut migrated, query(1, 20200101, 20201231, 1))
+        );
+        remove_test_file(path);
+    }
+
+    #[test]
+    fn test_estimate_file_size() {
+        let path = "test_estimate_file_size.db";
+        for keys_per_page in 1..5 {
+            let page_size = page_size_for_keys(keys_per_page) as u32;
+            for num_keys in [0, 1, 2, 5, 17, 64, 100, 1000] {
+                remove_test_file(path);
+                let mut inputs = (0..num_keys).map(|date| (key(0, date, 0), date as Value));
+                BTree::write_from_iterator(path, page_size, &mut inputs).unwrap();
+                assert_eq!(
+                    fs::metadata(path).unwrap().len(),
+                    BTree::estimate_file_size(num_keys as usize, page_size).unwrap(),
+                    "{} keys with {} keys per page",
+                    num_keys,
+                    keys_per_page
+                );
+
+                // With wider values, an inner page holds more keys than a leaf does.
+                let page_size = page_size_for_keys_of::<f64>(keys_per_page) as u32;
+                let mut inputs = (0..num_keys).map(|date| (key(0, date, 0), date as f64));
+                BTree::write_values_from_iterator(path, page_size, &mut inputs).unwrap();
+                assert_eq!(
+                    fs::metadata(path).unwrap().len(),
+                    BTree::estimate_values_file_size::<f64>(num_keys as usize, page_size).unwrap(),
+                    "{} keys with {} f64 keys per page",
+                    num_keys,
+                    keys_per_page
+                );
+            }
+        }
+
+        for page_size in [0, PAGE_HEADER_SIZE as u32, page_size_for_keys(1) as u32 - 1] {
+            let error = BTree::estimate_file_size(100, page_size);
+            assert_eq!(ErrorKind::InvalidInput, error.unwrap_err().kind());
+        }
+        remove_test_file(path);
+    }
+
+    /// Writes a value for each of a hundred dates with `V`, then checks the values read back with a query and with
+    /// `get_latest_value`.
+    fn check_value_round_trip<V: ValueCodec + PartialEq>(path: &str, value_for: fn(u32) -> V) {
+        remove_test_file(path);
+        let mut inputs = (0..100).map(|date| (key(0, date, 0), value_for(date)));
+        let page_size = page_size_for_keys_of::<V>(4) as u32;
+        BTree::write_values_from_iterator(path, page_size, &mut inputs).unwrap();
+
+        let file = File::open(path).unwrap();
+        let mut btree = BTree::from_file(file, 10).unwrap();
+        btree.print().unwrap();
+        let mut iterator = btree.query_values::<V>(query(0, 0, 99, 0)).unwrap();
+        for date in (0..100).rev() {
+            let result = iterator.next().unwrap().unwrap();
+            assert_eq!(key(0, date, 0), result.key);
+            assert_eq!(value_for(date), result.value);
+        }
+        assert!(iterator.next().is_none());
+
+        let result = btree
+            .get_latest_value::<V>(AssetId(0), Date(42), Timestamp(0))
+            .unwrap();
+        assert_eq!(value_for(42), result.unwrap().value);
+    }
+
+    #[test]
+    fn test_i32_values() {
+        check_value_round_trip("test_i32_values.db", |date| {
+            date as i32 * 1_000_003 - 50_000_000
+        });
+    }
+
+    #[test]
+    fn test_f64_values() {
+        let path = "test_f64_values.db";
+        check_value_round_trip(path, |date| 1791.44000001 + date as f64 / 3.0);
+
+        // The file records that its values are f64s, so they can't be read as the default f32 `Value`.
+        let mut btree = BTree::from_file(File::open(path).unwrap(), 10).unwrap();
+        let error = btree
+            .get_latest(AssetId(0), Date(0), Timestamp(0))
+            .unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, error.kind());
+        let error = btree.query(query(0, 0, 99, 0)).err().unwrap();
+        assert_eq!(ErrorKind::InvalidData, error.kind());
+
+        // Nor can a header recording a width other than an f64's for them be opened.
+        let mut contents = fs::read(path).unwrap();
+        contents[7 * U32_SIZE..8 * U32_SIZE].copy_from_slice(&(f32::WIDTH as u32).to_be_bytes());
+        fs::write(path, contents).unwrap();
+        let error = BTree::from_file(File::open(path).unwrap(), 10)
+            .err()
+            .unwrap();
+        assert_eq!(ErrorKind::InvalidData, error.kind());
+
+        // An f32 file can't keep a price this precise.
+        let mut inputs = vec![(key(0, 0, 0), 1791.44000001f64 as Value)].into_iter();
+        BTree::write_from_iterator(path, page_size_for_keys(4) as u32, &mut inputs).unwrap();
+        let mut btree = BTree::from_file(File::open(path).unwrap(), 10).unwrap();
+        let result = btree.get_latest(AssetId(0), Date(0), Timestamp(0)).unwrap();
+        assert_ne!(1791.44000001, result.unwrap().value as f64);
+        remove_test_file(path);
+    }
+
+    #[test]
+    fn test_dictionary_code_values() {
+        check_value_round_trip("test_dictionary_code_values.db", |date| {
+            DictionaryCode((date % 11) as u16 + 60000)
+        });
+    }
+
+    #[test]
+    fn test_value_codec_mismatch() {
+        let path = "test_value_codec_mismatch.db";
+        check_value_round_trip(path, |date| date as i32);
+
+        let file = File::open(path).unwrap();
+        let mut btree = BTree::from_file(file, 10).unwrap();
+        let error = btree.query(query(0, 0, 99, 0)).err().unwrap();
+        assert_eq!(ErrorKind::InvalidData, error.kind());
+        let error = btree.query_values::<f64>(query(0, 0, 99, 0)).err().unwrap();
+        assert_eq!(ErrorKind::InvalidData, error.kind());
+    }
+
+    #[test]
+    fn test_fsck() {
+        let path = "test_fsck.db";
+        write_small(path);
+
+        let file = File::open(path).unwrap();
+        let mut btree = BTree::from_file(file, 10).unwrap();
+        assert_eq!(Ok(()), btree.fsck());
+        let root_page_num = btree.file_header.root_page_num;
+        let page_size = btree.file_header.page_size;
+
+        // Point the root's first child past the end of the file.
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .unwrap();
+        let offset =
+            FILE_HEADER_SIZE + (root_page_num * page_size) as usize + PAGE_HEADER_SIZE + KEY_SIZE;
+        file.seek(SeekFrom::Start(offset as u64)).unwrap();
+        file.write_all(&999u32.to_be_bytes()).unwrap();
+
+        let file = File::open(path).unwrap();
+        let mut btree = BTree::from_file(file, 10).unwrap();
+        let problems = btree.fsck().unwrap_err();
+        assert_eq!(
+            "Page 999 is out of range of the 9 pages in the file",
+            problems[0]
+        );
+    }
+
+    #[test]
+    fn test_fsck_leaf_links() {
+        let path = "test_fsck_leaf_links.db";
+        write_small(path);
+
+        // Break the back link from the second leaf to the first.
         let page_size = page_size_for_keys(3);
-        BTree::write_from_iterator(path, page_size as u32, &mut iter).unwrap();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .unwrap();
+        let offset = FILE_HEADER_SIZE + page_size + 2 * U32_SIZE;
+        file.seek(SeekFrom::Start(offset as u64)).unwrap();
+        file.write_all(&u32::MAX.to_be_bytes()).unwrap();
 
         let file = File::open(path).unwrap();
         let mut btree = BTree::from_file(file, 10).unwrap();
-        btree.print();
+        assert_eq!(
+            Err(vec![format!(
+                "Leaf 1 links back to page {} rather than 0",
+                u32::MAX
+            )]),
+            btree.fsck()
+        );
+    }
 
-        check_query(
-            &mut btree,
-            Query {
-                id: 0,
-                asset_id: 0,
-                start_date: 20200131,
-                end_date: 20200131,
-                timestamp: 20,
-            },
-            &[3.0],
-            1,
+    fn count_leaves(btree: &mut BTree) -> u32 {
+        let mut leaves = 0;
+        for page_num in 0..btree.file_header.page_count {
+            if btree.load_page(page_num).unwrap().is_leaf() {
+                leaves += 1;
+            }
+        }
+        leaves
+    }
+
+    #[test]
+    fn test_fill_factor() {
+        let path = "test_fill_factor.db";
+        let page_size = page_size_for_keys(10) as u32;
+        let inputs: Vec<(Key, Value)> = (0..1000)
+            .map(|date| (key(0, date, 0), date as Value))
+            .collect();
+
+        let mut leaf_counts = Vec::new();
+        for fill_factor in [1.0, 0.5] {
+            remove_test_file(path);
+            BTree::write_values_with_fill_factor(
+                path,
+                page_size,
+                fill_factor,
+                &mut inputs.clone().into_iter(),
+            )
+            .unwrap();
+
+            let file = File::open(path).unwrap();
+            let mut btree = BTree::from_file(file, 10).unwrap();
+            assert_eq!(Ok(()), btree.fsck());
+            leaf_counts.push(count_leaves(&mut btree));
+
+            let mut iterator = btree.query(query(0, 0, 999, 0)).unwrap();
+            for date in (0..1000).rev() {
+                assert_eq!(date as Value, iterator.next().unwrap().unwrap().value);
+            }
+            assert!(iterator.next().is_none());
+        }
+        assert_eq!(vec![100, 200], leaf_counts);
+
+        let mut inputs = inputs.into_iter();
+        for fill_factor in [0.0, 1.5, f32::NAN] {
+            let error =
+                BTree::write_values_with_fill_factor(path, page_size, fill_factor, &mut inputs)
+                    .unwrap_err();
+            assert_eq!(ErrorKind::InvalidInput, error.kind());
+        }
+    }
+
+    #[test]
+    fn test_fill_stats() {
+        let path = "test_fill_stats.db";
+        remove_test_file(path);
+        let page_size = page_size_for_keys(10) as u32;
+        let mut inputs = (0..95).map(|date| (key(0, date, 0), date as Value));
+        BTree::write_from_iterator(path, page_size, &mut inputs).unwrap();
+
+        // Nine full leaves of ten keys and a last leaf of five, under a root with a child for each.
+        let mut btree = BTree::from_file(File::open(path).unwrap(), 10).unwrap();
+        let stats = btree.fill_stats().unwrap();
+        assert_eq!(10, stats.leaves);
+        assert_eq!((9.0 + 0.5) / 10.0, stats.mean_occupancy);
+        assert_eq!(0.5, stats.min_occupancy);
+        assert_eq!(1.0, stats.max_occupancy);
+        assert_eq!(5 * KEY_VALUE_SIZE as u64, stats.slack_bytes);
+        assert_eq!(
+            vec![(10, 1)],
+            stats.inner_fanout.into_iter().collect::<Vec<_>>()
         );
-        check_query(
-            &mut btree,
-            Query {
-                id: 0,
-                asset_id: 0,
-                start_date: 20200131,
-                end_date: 20200131,
-                timestamp: 15,
-            },
-            &[2.0],
-            1,
+        remove_test_file(path);
+    }
+
+    #[test]
+    fn test_write_with_fanout() {
+        let path = "test_write_with_fanout.db";
+        remove_test_file(path);
+        let page_size = page_size_for_keys(10) as u32;
+        let inputs = |num_keys| (0..num_keys).map(|date| (key(0, date, 0), date as Value));
+
+        // A hundred keys fill ten leaves, which fit under a single root.
+        assert_eq!(Some(10), BTree::recommended_fanout(100, page_size, 2));
+        assert_eq!(None, BTree::recommended_fanout(100, page_size, 1));
+        BTree::write_with_fanout(path, page_size, 10, &mut inputs(100)).unwrap();
+        let mut btree = BTree::from_file(File::open(path).unwrap(), 10).unwrap();
+        assert_eq!(2, btree.height().unwrap());
+
+        // A thousand keys fill a hundred leaves, which take three levels with the inner pages packed full, and the
+        // fewest children per inner page that keeps them to three is ten.
+        BTree::write_from_iterator(path, page_size, &mut inputs(1000)).unwrap();
+        let mut btree = BTree::from_file(File::open(path).unwrap(), 10).unwrap();
+        assert_eq!(3, btree.height().unwrap());
+        assert_eq!(None, BTree::recommended_fanout(1000, page_size, 2));
+        assert_eq!(Some(10), BTree::recommended_fanout(1000, page_size, 3));
+        assert_eq!(Some(5), BTree::recommended_fanout(1000, page_size, 4));
+        for page_size in [0, PAGE_HEADER_SIZE as u32, page_size_for_keys(1) as u32 - 1] {
+            assert_eq!(None, BTree::recommended_fanout(1000, page_size, 3));
+        }
+
+        for (fanout, height, inner_pages) in [(10, 3, 11), (5, 4, 25)] {
+            BTree::write_with_fanout(path, page_size, fanout, &mut inputs(1000)).unwrap();
+            let mut btree = BTree::from_file(File::open(path).unwrap(), 10).unwrap();
+            assert_eq!(height, btree.height().unwrap());
+            assert_eq!(Ok(()), btree.fsck());
+            let stats = btree.fill_stats().unwrap();
+            assert_eq!(100, stats.leaves);
+            assert_eq!(inner_pages, stats.inner_fanout.values().sum::<usize>());
+            assert!(stats
+                .inner_fanout
+                .keys()
+                .all(|&children| children <= fanout));
+
+            let values = query_values(&mut btree, query(0, 0, 999, 0));
+            let expected: Vec<Value> = (0..1000).rev().map(|date| date as Value).collect();
+            assert_eq!(expected, values);
+        }
+
+        for fanout in [1, 12] {
+            let error =
+                BTree::write_with_fanout(path, page_size, fanout, &mut inputs(100)).unwrap_err();
+            assert_eq!(ErrorKind::InvalidInput, error.kind());
+        }
+        remove_test_file(path);
+    }
+
+    #[test]
+    fn test_read_csv_errors() {
+        let path = "test_read_csv_errors.csv";
+        fs::write(
+            path,
+            b"0,20200131,0,1.0\r\n0,20200229,0,Caf\xe9\n0,20200331,x,2.0\n",
+        )
+        .unwrap();
+
+        let rows: Vec<std::io::Result<(Key, Value)>> = read_csv(path).unwrap().collect();
+        assert_eq!(3, rows.len());
+        assert_eq!((key(0, 20200131, 0), 1.0), *rows[0].as_ref().unwrap());
+
+        let error = rows[1].as_ref().unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, error.kind());
+        let message = error.to_string();
+        assert!(
+            message.starts_with("Line 2 isn't valid UTF-8"),
+            "{}",
+            message
         );
-        check_query(
-            &mut btree,
-            Query {
-                id: 0,
-                asset_id: 0,
-                start_date: 20200115,
-                end_date: 20200405,
-                timestamp: 20,
-            },
-            &[120.0, 12.0, 3.0],
-            3,
+        assert!(message.ends_with(": 0,20200229,0,Caf\\xe9"), "{}", message);
+
+        let error = rows[2].as_ref().unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, error.kind());
+        assert_eq!(
+            "Line 3 has no valid timestamp: 0,20200331,x,2.0",
+            error.to_string()
         );
-        check_query(
-            &mut btree,
-            Query {
-                id: 0,
-                asset_id: 1,
-                start_date: 20200315,
-                end_date: 20200515,
-                timestamp: 21,
-            },
-            &[2200.0, 220.0],
-            2,
+
+        remove_test_file(path);
+        assert_eq!(ErrorKind::NotFound, read_csv(path).err().unwrap().kind());
+    }
+
+    #[test]
+    fn test_read_quoted_csv() {
+        let path = "test_read_quoted_csv.csv";
+        fs::write(
+            path,
+            "0,20200131,0,1.0,\"Apple, Inc.\",\"The \"\"fruit\"\" company\"\r\n\
+             \"0\",20200229,1,2.0,\"Line one\nline two\"\r\n\
+             0,20200331,0,3.0\n",
+        )
+        .unwrap();
+        let rows: Vec<(Key, Value)> = read_csv(path).unwrap().map(Result::unwrap).collect();
+        assert_eq!(
+            vec![
+                (key(0, 20200131, 0), 1.0),
+                (key(0, 20200229, 1), 2.0),
+                (key(0, 20200331, 0), 3.0)
+            ],
+            rows
         );
+
+        fs::write(path, "0;20200131;0;1.0;'Apple; Inc.'\n0;20200229;x;2.0\n").unwrap();
+        let format = CsvFormat {
+            delimiter: b';',
+            quote: b'\'',
+            ..CsvFormat::default()
+        };
+        let rows: Vec<std::io::Result<(Key, Value)>> =
+            read_csv_with_format(path, format).unwrap().collect();
+        assert_eq!((key(0, 20200131, 0), 1.0), *rows[0].as_ref().unwrap());
+        assert_eq!(
+            "Line 2 has no valid timestamp: 0;20200229;x;2.0",
+            rows[1].as_ref().unwrap_err().to_string()
+        );
+
+        remove_test_file(path);
     }
 
-    fn check_query(btree: &mut BTree, query: Query, expected: &[f32], pages_read: u32) {
-        let mut iterator = btree.query(query).unwrap();
+    #[test]
+    fn test_read_separated_dates() {
+        let path = "test_read_separated_dates.csv";
+        let db_path = "test_read_separated_dates.db";
+        fs::write(
+            path,
+            "0,2020-01-31,0,1.0\n0,2020/02/29,0,2.0\n1,2020-03-31,0,3.0\n1,20200430,0,4.0\n1,2021-02-29,0,5.0\n",
+        )
+        .unwrap();
 
-        for i in 0..expected.len() {
-            match iterator.next() {
-                Some(Ok(v)) => assert_eq!(v.value, expected[i]),
-                _ => panic!("Iterator ran out of elements"),
+        let format = CsvFormat {
+            separated_dates: true,
+            ..CsvFormat::default()
+        };
+        let rows: Vec<std::io::Result<(Key, Value)>> =
+            read_csv_with_format(path, format).unwrap().collect();
+        assert_eq!(5, rows.len());
+        for (line_number, line) in [(4, "1,20200430,0,4.0"), (5, "1,2021-02-29,0,5.0")] {
+            assert_eq!(
+                format!("Line {} has no valid date: {}", line_number, line),
+                rows[line_number - 1].as_ref().unwrap_err().to_string()
+            );
+        }
+
+        // The dates are packed as they're read, so they're queried as any other dates are.
+        let mut rows = rows.into_iter().take(3).map(Result::unwrap);
+        BTree::write_from_iterator(db_path, page_size_for_keys(3) as u32, &mut rows).unwrap();
+        let mut btree = BTree::from_file(File::open(db_path).unwrap(), 10).unwrap();
+        assert_eq!(
+            vec![2.0, 1.0],
+            query_values(&mut btree, query(0, 20200101, 20201231, 0))
+        );
+        assert_eq!(
+            vec![3.0],
+            query_values(&mut btree, query(1, 20200331, 20200331, 0))
+        );
+
+        let error = read_csv(path).unwrap().next().unwrap().unwrap_err();
+        assert_eq!(
+            "Line 1 has no valid date: 0,2020-01-31,0,1.0",
+            error.to_string()
+        );
+
+        remove_test_file(path);
+        remove_test_file(db_path);
+    }
+
+    #[test]
+    fn test_check_key_order() {
+        let mut page_buf = PageBuffer::new(page_size_for_keys(3) as u32, LEAF_TYPE, Value::WIDTH);
+        page_buf.set_key(0, key(0, 20200131, 10));
+        page_buf.set_key(1, key(0, 20200229, 5));
+        page_buf.set_key(2, key(0, 20200229, 5));
+        page_buf.set_num_keys(3);
+        assert_eq!(Ok(()), page_buf.check_key_order());
+
+        page_buf.set_key(2, key(0, 20200131, 20));
+        assert_eq!(Err(2), page_buf.check_key_order());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Keys out of order in page 0")]
+    fn test_write_out_of_order_keys() {
+        let path = "test_write_out_of_order_keys.db";
+        remove_test_file(path);
+
+        let inputs = vec![(key(0, 20200229, 5), 11.0), (key(0, 20200131, 0), 1.0)];
+        let page_size = page_size_for_keys(3);
+        BTree::write_from_iterator(path, page_size as u32, &mut inputs.into_iter()).unwrap();
+    }
+
+    #[test]
+    fn test_write_multi_level_tree() {
+        let path = "test_write_multi_level_tree.db";
+        remove_test_file(path);
+
+        let inputs: Vec<(Key, Value)> = (0..100)
+            .map(|i| (key(i / 10, 20200101 + i % 10, 0), i as Value))
+            .collect();
+        let page_size = page_size_for_keys(3) as u32;
+        BTree::write_from_iterator(path, page_size, &mut inputs.into_iter()).unwrap();
+
+        let file = File::open(path).unwrap();
+        let file_size = file.metadata().unwrap().len();
+        let mut btree = BTree::from_file(file, 0).unwrap();
+        assert_eq!(Ok(()), btree.fsck());
+        assert_eq!(
+            BTree::estimate_file_size(100, page_size).unwrap(),
+            file_size
+        );
+        remove_test_file(path);
+    }
+
+    #[test]
+    fn test_level_keys() {
+        let path = "test_level_keys.db";
+        remove_test_file(path);
+
+        let inputs: Vec<(Key, Value)> = (0..100)
+            .map(|i| (key(i / 10, 20200101 + i % 10, 0), i as Value))
+            .collect();
+        let keys: Vec<Key> = inputs.iter().map(|(key, _)| *key).collect();
+        let page_size = page_size_for_keys(3) as u32;
+        BTree::write_from_iterator(path, page_size, &mut inputs.into_iter()).unwrap();
+
+        // 34 leaves of 3 keys, under 9 inner pages of up to 4 children, under 3, under the root.
+        let mut btree = BTree::from_file(File::open(path).unwrap(), 0).unwrap();
+        assert_eq!(vec![keys[48], keys[96]], btree.level_keys(0).unwrap());
+        let level_1: Vec<Key> = [12, 24, 36, 60, 72, 84].iter().map(|&i| keys[i]).collect();
+        assert_eq!(level_1, btree.level_keys(1).unwrap());
+        let level_2: Vec<Key> = (1..34)
+            .filter(|i| i % 4 != 0)
+            .map(|i| keys[i * 3])
+            .collect();
+        assert_eq!(level_2, btree.level_keys(2).unwrap());
+        let error = btree.level_keys(3).unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, error.kind());
+        remove_test_file(path);
+    }
+
+    #[test]
+    fn test_find_duplicates() {
+        let path = "test_find_duplicates.db";
+        remove_test_file(path);
+
+        write_small(path);
+        let mut btree = BTree::from_file(File::open(path).unwrap(), 4).unwrap();
+        assert_eq!(Vec::<Key>::new(), btree.find_duplicates().unwrap());
+
+        // The repeated key straddles two leaves, and is written three times.
+        let mut inputs = small_inputs();
+        let duplicate = inputs[8];
+        inputs.insert(8, duplicate);
+        inputs.insert(8, duplicate);
+        let page_size = page_size_for_keys(3) as u32;
+        BTree::write_from_iterator(path, page_size, &mut inputs.into_iter()).unwrap();
+        let mut btree = BTree::from_file(File::open(path).unwrap(), 4).unwrap();
+        assert_eq!(vec![duplicate.0], btree.find_duplicates().unwrap());
+        remove_test_file(path);
+    }
+
+    #[test]
+    fn test_delta_encoding() {
+        let path = "test_delta_encoding.db";
+        let plain_path = "test_delta_encoding_plain.db";
+        remove_test_file(path);
+
+        let mut rng = Rng(17);
+        let inputs: Vec<(Key, Value)> = (0..500)
+            .map(|i| {
+                let price = 100.0 + (i % 50) as Value + rng.below(1000) as Value / 1000.0;
+                (key(i / 50, 20200101 + i % 50, 0), price)
+            })
+            .collect();
+        let page_size = page_size_for_keys(64) as u32;
+        BTree::write_delta_encoded_from_iterator(path, page_size, &mut inputs.clone().into_iter())
+            .unwrap();
+        BTree::write_from_iterator(plain_path, page_size, &mut inputs.clone().into_iter()).unwrap();
+        assert!(fs::metadata(path).unwrap().len() < fs::metadata(plain_path).unwrap().len());
+
+        // With the keys packed as well, each leaf holds twice as many entries.
+        let mut plain = BTree::from_file(File::open(plain_path).unwrap(), 4).unwrap();
+        assert_eq!(8, plain.fill_stats().unwrap().leaves);
+        let mut btree = BTree::from_file(File::open(path).unwrap(), 4).unwrap();
+        assert_eq!(4, btree.fill_stats().unwrap().leaves);
+
+        // The values in each leaf span less than 51, so each one reads back within half of 51/65535.
+        let file = File::open(path).unwrap();
+        let mut btree = BTree::from_file(file, 4).unwrap();
+        assert_eq!(Ok(()), btree.fsck());
+        for (key, value) in inputs.iter() {
+            let result = btree.get_latest(key.asset_id, key.date, key.timestamp);
+            let delta_value = result.unwrap().unwrap().value;
+            assert!((delta_value - value).abs() <= 51.0 / 65535.0, "{:?}", key);
+        }
+        let mut iterator = btree.query(query(3, 20200101, 20200131, 0)).unwrap();
+        let mut count = 0;
+        while let Some(result) = iterator.next() {
+            result.unwrap();
+            count += 1;
+        }
+        assert_eq!(31, count);
+
+        // Each of these keys is too far from the one before it to be packed with it, so each starts a new leaf.
+        let far_inputs = vec![
+            (key(0, 20200101, 0), 1.0),
+            (key(0, 20200101, 1 << 40), 2.0),
+            (key(0, 20200102, 0), 3.0),
+            (key(70_000, 20200101, 0), 4.0),
+            (key(70_000, 20250101, 0), 5.0),
+        ];
+        BTree::write_delta_encoded_from_iterator(
+            path,
+            page_size,
+            &mut far_inputs.clone().into_iter(),
+        )
+        .unwrap();
+        let mut btree = BTree::from_file(File::open(path).unwrap(), 4).unwrap();
+        assert_eq!(Ok(()), btree.fsck());
+        assert_eq!(5, btree.fill_stats().unwrap().leaves);
+        for (key, value) in far_inputs {
+            let result = btree.get_latest(key.asset_id, key.date, key.timestamp);
+            assert_eq!(value, result.unwrap().unwrap().value, "{:?}", key);
+        }
+
+        let mut bad_inputs = vec![(key(0, 20200101, 0), f32::NAN)].into_iter();
+        let error = BTree::write_delta_encoded_from_iterator(path, page_size, &mut bad_inputs);
+        assert_eq!(ErrorKind::InvalidInput, error.unwrap_err().kind());
+
+        remove_test_file(path);
+        remove_test_file(plain_path);
+    }
+
+    #[test]
+    fn test_reused_inner_pages_are_cleared() {
+        let path = "test_reused_inner_pages_are_cleared.db";
+        remove_test_file(path);
+
+        let inputs: Vec<(Key, Value)> = (0..1000)
+            .map(|i| (key(i / 100, 20200101 + i % 100, 0), i as Value))
+            .collect();
+        let page_size = page_size_for_keys(3) as u32;
+        BTree::write_from_iterator(path, page_size, &mut inputs.clone().into_iter()).unwrap();
+
+        let file = File::open(path).unwrap();
+        let mut btree = BTree::from_file(file, 0).unwrap();
+        assert_eq!(Ok(()), btree.fsck());
+        for (key, value) in inputs {
+            let result = btree.get_latest(key.asset_id, key.date, key.timestamp);
+            assert_eq!(value, result.unwrap().unwrap().value);
+        }
+
+        // Past the last entry it uses, every page should be zeroed, whether or not its buffer held another page before.
+        for page_num in 0..btree.file_header.page_count {
+            let page = btree.load_page(page_num).unwrap();
+            let used = if page.is_leaf() {
+                page.key_offset(page.num_keys() as usize)
+            } else {
+                page.key_offset(min(page.num_keys() as usize + 1, page.key_capacity()))
             };
+            assert!(page.buf()[used..].iter().all(|b| *b == 0), "{}", page_num);
         }
+        remove_test_file(path);
+    }
 
-        assert_eq!(iterator.pages_read, pages_read);
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Page 1 isn't being written at its offset")]
+    fn test_write_page_at_wrong_offset() {
+        let path = "test_write_page_at_wrong_offset.db";
+        remove_test_file(path);
+
+        let page_size = page_size_for_keys(3) as u32;
+        let file = File::create(path).unwrap();
+        let mut writer =
+            TreeWriter::create(file, page_size, LEAF_TYPE, Value::ID, Value::WIDTH).unwrap();
+        let page_buf = PageBuffer::new(page_size, LEAF_TYPE, Value::WIDTH);
+        writer.write_page(&page_buf).unwrap();
+        writer.out.write_all(&[0]).unwrap();
+        writer.write_page(&page_buf).unwrap();
+    }
+
+    #[test]
+    fn test_failed_write_leaves_target_alone() {
+        let path = "test_failed_write_leaves_target_alone.db";
+        let temp_path = "test_failed_write_leaves_target_alone.db.tmp";
+        remove_test_file(path);
+
+        // The NaN can't be delta encoded, so the write fails after the first two leaves.
+        let failing_rows = || {
+            (0..10u32).map(|day| {
+                let value = if day == 7 { f32::NAN } else { day as f32 };
+                (key(0, 20200101 + day, 10), value)
+            })
+        };
+        let page_size = page_size_for_keys(3) as u32;
+        assert!(
+            BTree::write_delta_encoded_from_iterator(path, page_size, &mut failing_rows()).is_err()
+        );
+        assert!(!Path::new(path).exists());
+        assert!(!Path::new(temp_path).exists());
+
+        write_small(path);
+        let contents = fs::read(path).unwrap();
+        assert!(
+            BTree::write_delta_encoded_from_iterator(path, page_size, &mut failing_rows()).is_err()
+        );
+        assert_eq!(contents, fs::read(path).unwrap());
+        assert!(!Path::new(temp_path).exists());
+        remove_test_file(path);
+    }
+
+    /// Small xorshift generator, so that the randomized tests are reproducible without any extra dependencies.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u32) -> u32 {
+            (self.next() % (n as u64)) as u32
+        }
+    }
+
+    /// Generates a sorted key set for between zero and four assets with a random number of dates per asset and
+    /// timestamps per date, occasionally repeating a key.
+    fn random_inputs(rng: &mut Rng) -> Vec<(Key, Value)> {
+        let mut inputs = Vec::new();
+        let mut asset_id = rng.below(3);
+        for _ in 0..rng.below(5) {
+            let mut date = 20200101 + rng.below(3);
+            for _ in 0..rng.below(20) {
+                let mut timestamp = rng.below(3) as u64;
+                for _ in 0..(1 + rng.below(3)) {
+                    let key = key(asset_id, date, timestamp);
+                    inputs.push((key, inputs.len() as Value));
+                    if rng.below(10) == 0 {
+                        for _ in 0..(1 + rng.below(4)) {
+                            inputs.push((key, inputs.len() as Value));
+                        }
+                    }
+                    timestamp += 1 + rng.below(10) as u64;
+                }
+                date += 1 + rng.below(3);
+            }
+            asset_id += 1 + rng.below(2);
+        }
+        inputs
+    }
+
+    /// The rows a query should yield: the last row written as of the query's timestamp for each date in the range,
+    /// from the latest date to the earliest.
+    fn expected_results(inputs: &[(Key, Value)], query: &Query) -> Vec<(Key, Value)> {
+        let mut expected: Vec<(Key, Value)> = Vec::new();
+        for (key, value) in inputs.iter().rev() {
+            if key.asset_id == query.asset_id
+                && key.date >= query.start_date
+                && key.date <= query.end_date
+                && key.timestamp <= query.timestamp
+                && expected
+                    .last()
+                    .is_none_or(|(last, _)| last.date != key.date)
+            {
+                expected.push((*key, *value));
+            }
+        }
+        expected
+    }
+
+    #[test]
+    fn test_randomized_round_trip() {
+        let path = "test_randomized_round_trip.db";
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+
+        for _ in 0..200 {
+            remove_test_file(path);
+            let inputs = random_inputs(&mut rng);
+            let keys_per_page = 1 + rng.below(6);
+            let page_size =
+                page_size_for_keys(keys_per_page) + rng.below(KEY_VALUE_SIZE as u32) as usize;
+            BTree::write_from_iterator(path, page_size as u32, &mut inputs.clone().into_iter())
+                .unwrap();
+
+            let file = File::open(path).unwrap();
+            let mut btree = BTree::from_file(file, rng.below(4) as usize).unwrap();
+            assert_eq!(Ok(()), btree.fsck());
+            for _ in 0..20 {
+                let start_date = 20200100 + rng.below(50);
+                let query = query(
+                    rng.below(10),
+                    start_date,
+                    start_date + rng.below(50),
+                    rng.below(40) as u64,
+                );
+                let expected = expected_results(&inputs, &query);
+                let (asset_id, end_date, timestamp) =
+                    (query.asset_id, query.end_date, query.timestamp);
+
+                let mut actual = Vec::new();
+                let mut iterator = btree.query(query).unwrap();
+                while let Some(result) = iterator.next() {
+                    let result = result.unwrap();
+                    actual.push((result.key, result.value));
+                }
+                assert_eq!(expected, actual);
+
+                let expected_latest = expected.first().filter(|(key, _)| key.date == end_date);
+                let actual_latest = btree
+                    .get_latest(asset_id, end_date, timestamp)
+                    .unwrap()
+                    .map(|result| (result.key, result.value));
+                assert_eq!(expected_latest.copied(), actual_latest);
+
+                let points: Vec<(AssetId, Date)> = (0..rng.below(10))
+                    .map(|_| (AssetId(rng.below(10)), Date(20200100 + rng.below(100))))
+                    .collect();
+                let expected_points: Vec<Option<(Key, Value)>> = points
+                    .iter()
+                    .map(|&(asset_id, date)| {
+                        inputs.iter().rev().copied().find(|(key, _)| {
+                            key.asset_id == asset_id
+                                && key.date == date
+                                && key.timestamp <= timestamp
+                        })
+                    })
+                    .collect();
+                let actual_points: Vec<Option<(Key, Value)>> = btree
+                    .query_points(&points, timestamp)
+                    .unwrap()
+                    .into_iter()
+                    .map(|result| result.map(|result| (result.key, result.value)))
+                    .collect();
+                assert_eq!(expected_points, actual_points);
+            }
+        }
     }
 }
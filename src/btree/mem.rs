@@ -1,3 +1,4 @@
+use crate::btree::node::{self, Direction, LeafNode, Step};
 use std::borrow::Borrow;
 use std::cell::{Ref, RefCell};
 use std::rc::{Rc, Weak};
@@ -19,6 +20,7 @@ trait Node {
     fn delete(&mut self, key: u32) -> Option<u32>;
     fn merge(&mut self, midpoint_key: u32, other: &Rc<RefCell<dyn Node>>) -> bool;
     fn add_to_graph_vis(&self, graphviz: &mut GraphViz) -> usize;
+    fn first_leaf(&self) -> Weak<RefCell<Leaf>>;
 
     fn count_nodes(&self) -> (usize, usize) {
         (1, 0)
@@ -46,6 +48,7 @@ impl Leaf {
         }
     }
 
+    #[cfg(test)]
     fn from_kv(capacity: usize, kv: &[(u32, u32)]) -> Leaf {
         let mut leaf = Leaf {
             kv: Vec::with_capacity(capacity),
@@ -57,6 +60,28 @@ impl Leaf {
     }
 }
 
+impl LeafNode for Leaf {
+    type Key = u32;
+    type Value = u32;
+    type Sibling = Rc<RefCell<Leaf>>;
+
+    fn num_keys(&self) -> usize {
+        self.kv.len()
+    }
+
+    fn key_at(&self, index: usize) -> u32 {
+        self.kv[index].0
+    }
+
+    fn value_at(&self, index: usize) -> u32 {
+        self.kv[index].1
+    }
+
+    fn sibling(&self) -> Option<Rc<RefCell<Leaf>>> {
+        self.next.upgrade()
+    }
+}
+
 impl Node for Leaf {
     fn lookup(&self, key: u32) -> Option<u32> {
         self.kv
@@ -66,10 +91,7 @@ impl Node for Leaf {
     }
 
     fn lookup_range(&self, from_key: u32, to_key: u32) -> LookupRangeIterator {
-        let index = match self.kv.binary_search_by_key(&from_key, |value| value.0) {
-            Ok(index) => index,
-            Err(index) => index,
-        };
+        let index = Some(self.index_from(&from_key)).filter(|&index| index < self.kv.len());
 
         LookupRangeIterator {
             leaf: Weak::clone(&self.this),
@@ -146,12 +168,16 @@ impl Node for Leaf {
         graphviz.add_leaf_node(&self.kv)
     }
 
+    fn first_leaf(&self) -> Weak<RefCell<Leaf>> {
+        Weak::clone(&self.this)
+    }
+
     fn merge_into_leaf(&mut self, other: &mut Leaf) -> bool {
         if self.kv.len() + other.kv.len() > other.kv.capacity() {
             false
         } else {
             other.kv.extend(self.kv.drain(0..self.kv.len()));
-            other.next = Weak::clone(&other.next);
+            other.next = Weak::clone(&self.next);
             true
         }
     }
@@ -159,7 +185,7 @@ impl Node for Leaf {
 
 pub struct LookupRangeIterator {
     leaf: Weak<RefCell<Leaf>>,
-    index: usize,
+    index: Option<usize>,
     to_key: u32,
 }
 
@@ -167,7 +193,7 @@ impl LookupRangeIterator {
     // fn empty() -> LookupRangeIterator {
     //     LookupRangeIterator {
     //         leaf: Weak::new(),
-    //         index: 0,
+    //         index: None,
     //         to_key: 0,
     //     }
     // }
@@ -177,23 +203,19 @@ impl Iterator for LookupRangeIterator {
     type Item = u32;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.leaf.upgrade() {
-            Some(leaf) => {
-                let leaf: &RefCell<Leaf> = leaf.borrow();
-                let leaf: Ref<Leaf> = leaf.borrow();
-                if self.index >= leaf.kv.len() {
-                    self.leaf = Weak::clone(&leaf.next);
-                    self.index = 0;
-                    self.next()
-                } else if leaf.kv[self.index].0 <= self.to_key {
-                    let res = Some(leaf.kv[self.index].1);
-                    self.index += 1;
-                    res
-                } else {
-                    None
+        loop {
+            let leaf = self.leaf.upgrade()?;
+            let leaf: &RefCell<Leaf> = leaf.borrow();
+            let leaf: Ref<Leaf> = leaf.borrow();
+            match node::step(&*leaf, &mut self.index, Direction::Ascending) {
+                Step::Entry(key, index) if key <= self.to_key => return Some(leaf.value_at(index)),
+                Step::Entry(..) | Step::End => return None,
+                Step::Sibling(sibling) => {
+                    self.index =
+                        node::first_index(&*sibling.as_ref().borrow(), Direction::Ascending);
+                    self.leaf = Rc::downgrade(&sibling);
                 }
             }
-            None => None,
         }
     }
 }
@@ -331,7 +353,13 @@ impl Node for InternalNode {
             let target_id = target.borrow().add_to_graph_vis(graphviz);
             graphviz.add_edge(node_id, i, target_id);
         }
-        return node_id;
+        node_id
+    }
+
+    fn first_leaf(&self) -> Weak<RefCell<Leaf>> {
+        let first: &RefCell<dyn Node> = self.pointers[0].borrow();
+        let first: Ref<dyn Node> = first.borrow();
+        first.first_leaf()
     }
 
     fn count_nodes(&self) -> (usize, usize) {
@@ -384,6 +412,23 @@ impl BTree {
         })
     }
 
+    /// Returns the keys of each leaf, following the leaves' next links from the leftmost leaf. Handy for spotting
+    /// leaves that have dropped out of the chain, since range lookups walk the same links.
+    pub fn leaf_chain_keys(&self) -> Vec<Vec<u32>> {
+        let mut chain = Vec::new();
+        let mut leaf = self.root.as_ref().map_or_else(Weak::new, |root| {
+            let root: &RefCell<dyn Node> = root.borrow();
+            let root: Ref<dyn Node> = root.borrow();
+            root.first_leaf()
+        });
+        while let Some(current) = leaf.upgrade() {
+            let current = current.as_ref().borrow();
+            chain.push(current.kv.iter().map(|(key, _)| *key).collect());
+            leaf = Weak::clone(&current.next);
+        }
+        chain
+    }
+
     pub fn lookup(&mut self, key: u32) -> Option<u32> {
         self.root.as_ref().and_then(|root| {
             let root: &RefCell<dyn Node> = root.borrow();
@@ -715,13 +760,13 @@ mod tests {
         }
         btree.print();
 
-        for i in 0..seq.len() {
-            let initial_value = btree.lookup(seq[i]);
-            let orig_value = btree.delete(seq[i]);
+        for (i, key) in seq.iter().enumerate() {
+            let initial_value = btree.lookup(*key);
+            let orig_value = btree.delete(*key);
             assert_eq!(initial_value, orig_value);
 
-            for j in (i + 1)..seq.len() {
-                assert_eq!(Some(seq[j] * 100), btree.lookup(seq[j]));
+            for remaining in seq[(i + 1)..].iter() {
+                assert_eq!(Some(*remaining * 100), btree.lookup(*remaining));
             }
         }
         for i in seq.iter() {
@@ -730,13 +775,35 @@ mod tests {
         // assert_eq!((1, 0), btree.count_nodes(1, 0));
         // btree.print();
 
-        for i in 0..25 {
-            btree.insert(seq[i], seq[i] * 100);
-            assert_eq!(Some(seq[i] * 100), btree.lookup(seq[i]));
+        for i in seq.iter().take(25) {
+            btree.insert(*i, *i * 100);
+            assert_eq!(Some(*i * 100), btree.lookup(*i));
         }
         btree.print();
     }
 
+    #[test]
+    fn leaf_chain_after_merges() {
+        let mut btree = BTree::new(3);
+        for i in 0..30 {
+            btree.insert(i, i * 10);
+        }
+        for i in (0..30).filter(|i| i % 3 != 0) {
+            assert_eq!(Some(i * 10), btree.delete(i));
+        }
+
+        let chain = btree.leaf_chain_keys();
+        assert_eq!(btree.count_nodes().0, chain.len());
+        assert_eq!(
+            (0..30).step_by(3).collect_vec(),
+            chain.into_iter().flatten().collect_vec()
+        );
+        assert_eq!(
+            (0..30).step_by(3).map(|i| i * 10).collect_vec(),
+            btree.lookup_range(0, 30).collect_vec()
+        );
+    }
+
     #[test]
     fn leaf_node_lookup_range() {
         let seq = [10, 15, 13];
@@ -0,0 +1,132 @@
+// The parts of a range scan that don't depend on how a tree stores its leaves, shared by the in-memory tree in `mem`
+// and the file-backed tree in `file`.
+
+/// The operations a range scan needs from a leaf.
+pub(crate) trait LeafNode {
+    type Key: Ord;
+    type Value;
+    /// A handle on another leaf, from which the tree can get at that leaf.
+    type Sibling;
+
+    fn num_keys(&self) -> usize;
+    fn key_at(&self, index: usize) -> Self::Key;
+    fn value_at(&self, index: usize) -> Self::Value;
+
+    /// The next leaf in the direction the tree scans its leaves, if there is one.
+    fn sibling(&self) -> Option<Self::Sibling>;
+
+    /// Returns the index of the first key that's at least the given key.
+    fn index_from(&self, key: &Self::Key) -> usize {
+        partition_point(self.num_keys(), |index| self.key_at(index) < *key)
+    }
+}
+
+/// Returns the index of the first of `len` positions for which the predicate is false, where the predicate holds for
+/// every position before that one and none after it.
+pub(crate) fn partition_point(len: usize, pred: impl Fn(usize) -> bool) -> usize {
+    let mut min = 0;
+    let mut max = len;
+    while min < max {
+        let midpoint = (max + min) / 2;
+        if pred(midpoint) {
+            min = midpoint + 1;
+        } else {
+            max = midpoint;
+        }
+    }
+    min
+}
+
+/// The order a scan visits the keys of a leaf in.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Direction {
+    Ascending,
+    Descending,
+}
+
+/// What a scan finds at its position.
+pub(crate) enum Step<K, S> {
+    /// The key at the position, along with its index, from which the caller can decode the value if it needs it.
+    Entry(K, usize),
+    /// The leaf has no more entries, and the scan carries on from the start of its sibling.
+    Sibling(S),
+    /// The leaf has no more entries, and there's no sibling.
+    End,
+}
+
+/// The position of the first entry a scan visits in a leaf, or None if the leaf is empty.
+pub(crate) fn first_index<L: LeafNode>(leaf: &L, direction: Direction) -> Option<usize> {
+    match (leaf.num_keys(), direction) {
+        (0, _) => None,
+        (_, Direction::Ascending) => Some(0),
+        (num_keys, Direction::Descending) => Some(num_keys - 1),
+    }
+}
+
+/// Takes a step of a scan from a position within a leaf, where None means the scan has passed the leaf's last entry,
+/// moving the position on to the next entry.
+pub(crate) fn step<L: LeafNode>(
+    leaf: &L,
+    index: &mut Option<usize>,
+    direction: Direction,
+) -> Step<L::Key, L::Sibling> {
+    match *index {
+        Some(current) => {
+            *index = match direction {
+                Direction::Ascending if current + 1 < leaf.num_keys() => Some(current + 1),
+                Direction::Descending if current > 0 => Some(current - 1),
+                _ => None,
+            };
+            Step::Entry(leaf.key_at(current), current)
+        }
+        None => match leaf.sibling() {
+            Some(sibling) => Step::Sibling(sibling),
+            None => Step::End,
+        },
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::while_let_on_iterator)]
+mod tests {
+    use crate::btree::file::{self, page_size_for_keys, AssetId, Date, Key, Query, Timestamp};
+    use crate::btree::mem;
+    use std::fs::{self, File};
+
+    #[test]
+    fn test_backends_agree() {
+        let path = "test_backends_agree.db";
+        let keys: Vec<u32> = (0..50).map(|key| key * 2).collect();
+
+        let mut mem_tree = mem::BTree::new(4);
+        for &key in keys.iter().rev() {
+            assert!(mem_tree.insert(key, key * 10));
+        }
+        let mut rows = keys.iter().map(|&key| {
+            let key_value = (key * 10) as f32;
+            (Key::new(AssetId(1), Date(key), Timestamp(1)), key_value)
+        });
+        file::BTree::write_from_iterator(path, page_size_for_keys(4) as u32, &mut rows).unwrap();
+        let mut file_tree = file::BTree::from_file(File::open(path).unwrap(), 4).unwrap();
+
+        for (from, to) in [(0, 98), (5, 17), (10, 10), (11, 11), (90, 200), (150, 200)] {
+            let expected: Vec<u32> = mem_tree.lookup_range(from, to).collect();
+            let mut results = file_tree
+                .query(Query::new(
+                    0,
+                    AssetId(1),
+                    Date(from),
+                    Date(to),
+                    Timestamp(1),
+                ))
+                .unwrap();
+            let mut actual = Vec::new();
+            while let Some(result) = results.next() {
+                actual.push(result.unwrap().value() as u32);
+            }
+            actual.reverse();
+            assert_eq!(expected, actual, "Range {}..={}", from, to);
+        }
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,313 @@
+use crate::btree::file::Date;
+use std::collections::BTreeSet;
+
+// Calendar arithmetic on dates packed as YYYYMMDD.
+
+pub fn is_leap_year(year: u32) -> bool {
+    (year.is_multiple_of(4) && !year.is_multiple_of(100)) || year.is_multiple_of(400)
+}
+
+/// Returns the number of the last day in the month, taking leap years into account for February. Months are numbered
+/// from 1.
+pub fn last_day_of_month(year: u32, month: u32) -> u32 {
+    match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        _ => panic!("Invalid month {}", month),
+    }
+}
+
+/// The earliest date a calendar walks back to, the first day of year 1.
+pub const FIRST_DATE: Date = Date(10101);
+
+impl Date {
+    pub fn from_ymd(year: u32, month: u32, day: u32) -> Date {
+        Date(year * 10000 + month * 100 + day)
+    }
+
+    /// Parses a date written with separators, as `2020-01-31` or `2020/01/31`. Returns None unless the text is a
+    /// four digit year, a month and a day of that month, with the same separator between each.
+    pub fn parse_separated(text: &str) -> Option<Date> {
+        let separator = text.chars().find(|c| *c == '-' || *c == '/')?;
+        let fields: Vec<&str> = text.split(separator).collect();
+        let (year, month, day) = match fields[..] {
+            [year, month, day] if year.len() == 4 => (year, month, day),
+            _ => return None,
+        };
+        let number = |field: &str| -> Option<u32> {
+            if field.bytes().all(|b| b.is_ascii_digit()) {
+                field.parse().ok()
+            } else {
+                None
+            }
+        };
+        let (year, month, day) = (number(year)?, number(month)?, number(day)?);
+        if !(1..=12).contains(&month) || !(1..=last_day_of_month(year, month)).contains(&day) {
+            return None;
+        }
+        Some(Date::from_ymd(year, month, day))
+    }
+
+    pub fn year(&self) -> u32 {
+        self.0 / 10000
+    }
+
+    pub fn month(&self) -> u32 {
+        self.0 / 100 % 100
+    }
+
+    pub fn day(&self) -> u32 {
+        self.0 % 100
+    }
+
+    /// The last date in this date's month, which is the inclusive upper bound when querying a whole month.
+    pub fn end_of_month(&self) -> Date {
+        Date::from_ymd(
+            self.year(),
+            self.month(),
+            last_day_of_month(self.year(), self.month()),
+        )
+    }
+
+    /// The day of the week, counting from 0 for Monday to 6 for Sunday.
+    pub fn weekday(&self) -> u32 {
+        // Sakamoto's method, which counts from Sunday.
+        const MONTH_OFFSETS: [u32; 12] = [0, 3, 2, 5, 0, 3, 5, 1, 4, 6, 2, 4];
+        let year = if self.month() < 3 {
+            self.year() - 1
+        } else {
+            self.year()
+        };
+        let from_sunday = (year + year / 4 - year / 100
+            + year / 400
+            + MONTH_OFFSETS[self.month() as usize - 1]
+            + self.day())
+            % 7;
+        (from_sunday + 6) % 7
+    }
+
+    pub fn next_day(&self) -> Date {
+        if self.day() < last_day_of_month(self.year(), self.month()) {
+            Date::from_ymd(self.year(), self.month(), self.day() + 1)
+        } else if self.month() < 12 {
+            Date::from_ymd(self.year(), self.month() + 1, 1)
+        } else {
+            Date::from_ymd(self.year() + 1, 1, 1)
+        }
+    }
+
+    pub fn previous_day(&self) -> Date {
+        match (self.month(), self.day()) {
+            (1, 1) => Date::from_ymd(self.year() - 1, 12, 31),
+            (month, 1) => Date::from_ymd(
+                self.year(),
+                month - 1,
+                last_day_of_month(self.year(), month - 1),
+            ),
+            (month, day) => Date::from_ymd(self.year(), month, day - 1),
+        }
+    }
+}
+
+/// The dates a market is open, so that the days it's closed aren't taken for missing data. The default is open on
+/// every weekday.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Calendar {
+    AllDays,
+    /// Open Monday to Friday, apart from the given holidays.
+    Weekdays(BTreeSet<Date>),
+    /// Open only on the given dates.
+    Dates(BTreeSet<Date>),
+}
+
+impl Default for Calendar {
+    fn default() -> Calendar {
+        Calendar::Weekdays(BTreeSet::new())
+    }
+}
+
+impl Calendar {
+    pub fn is_open(&self, date: Date) -> bool {
+        match self {
+            Calendar::AllDays => true,
+            Calendar::Weekdays(holidays) => date.weekday() < 5 && !holidays.contains(&date),
+            Calendar::Dates(dates) => dates.contains(&date),
+        }
+    }
+
+    /// The latest date before the given one that's open, if there is one. Only dates from `FIRST_DATE` on are
+    /// looked at.
+    pub fn previous_open_day(&self, date: Date) -> Option<Date> {
+        match self {
+            Calendar::Dates(dates) => dates.range(FIRST_DATE..date).next_back().copied(),
+            _ => {
+                let mut date = date;
+                while date > FIRST_DATE {
+                    date = date.previous_day();
+                    if self.is_open(date) {
+                        return Some(date);
+                    }
+                }
+                None
+            }
+        }
+    }
+
+    /// The open dates from `start` to `end`, inclusive, in order.
+    pub fn open_days(&self, start: Date, end: Date) -> Vec<Date> {
+        match self {
+            Calendar::Dates(dates) => dates.range(start..=end).copied().collect(),
+            _ => std::iter::successors(Some(start), |date| Some(date.next_day()))
+                .take_while(|date| *date <= end)
+                .filter(|date| self.is_open(*date))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::btree::file::Date;
+    use crate::date::{is_leap_year, last_day_of_month, Calendar, FIRST_DATE};
+
+    #[test]
+    fn test_leap_years() {
+        assert!(is_leap_year(2020));
+        assert!(is_leap_year(2000));
+        assert!(!is_leap_year(2021));
+        assert!(!is_leap_year(1900));
+    }
+
+    #[test]
+    fn test_last_day_of_month() {
+        assert_eq!(29, last_day_of_month(2020, 2));
+        assert_eq!(29, last_day_of_month(2000, 2));
+        assert_eq!(28, last_day_of_month(2021, 2));
+        assert_eq!(28, last_day_of_month(1900, 2));
+        for month in [4, 6, 9, 11] {
+            assert_eq!(30, last_day_of_month(2021, month));
+        }
+        for month in [1, 3, 5, 7, 8, 10, 12] {
+            assert_eq!(31, last_day_of_month(2021, month));
+        }
+    }
+
+    #[test]
+    fn test_end_of_month() {
+        assert_eq!(Date(20200229), Date(20200201).end_of_month());
+        assert_eq!(Date(20210228), Date(20210215).end_of_month());
+        assert_eq!(Date(20210430), Date(20210430).end_of_month());
+        assert_eq!(Date(20211231), Date(20211201).end_of_month());
+    }
+
+    #[test]
+    fn test_parse_separated() {
+        assert_eq!(Some(Date(20200131)), Date::parse_separated("2020-01-31"));
+        assert_eq!(Some(Date(20200229)), Date::parse_separated("2020/02/29"));
+        assert_eq!(Some(Date(20200305)), Date::parse_separated("2020-3-5"));
+        for text in [
+            "20200131",
+            "2021-02-29",
+            "2020-13-01",
+            "2020-00-10",
+            "2020-01-00",
+            "2020-01/31",
+            "2020-01-31-01",
+            "20-01-31",
+            "2020-01-",
+            "2020-+1-31",
+        ] {
+            assert_eq!(None, Date::parse_separated(text), "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_weekday() {
+        assert_eq!(4, Date(20200131).weekday());
+        assert_eq!(5, Date(20200201).weekday());
+        assert_eq!(6, Date(20200202).weekday());
+        assert_eq!(0, Date(20200203).weekday());
+        assert_eq!(5, Date(20200229).weekday());
+        assert_eq!(4, Date(20210101).weekday());
+        assert_eq!(0, Date(20001225).weekday());
+    }
+
+    #[test]
+    fn test_next_day() {
+        assert_eq!(Date(20200316), Date(20200315).next_day());
+        assert_eq!(Date(20200229), Date(20200228).next_day());
+        assert_eq!(Date(20210301), Date(20210228).next_day());
+        assert_eq!(Date(20201201), Date(20201130).next_day());
+        assert_eq!(Date(20210101), Date(20201231).next_day());
+    }
+
+    #[test]
+    fn test_calendar() {
+        let weekdays = Calendar::default();
+        assert_eq!(
+            vec![Date(20200131), Date(20200203), Date(20200204)],
+            weekdays.open_days(Date(20200131), Date(20200204))
+        );
+        assert_eq!(
+            Some(Date(20200131)),
+            weekdays.previous_open_day(Date(20200203))
+        );
+        assert_eq!(
+            Some(Date(20200131)),
+            weekdays.previous_open_day(Date(20200202))
+        );
+
+        let holidays = Calendar::Weekdays(vec![Date(20200203)].into_iter().collect());
+        assert!(!holidays.is_open(Date(20200203)));
+        assert_eq!(
+            Some(Date(20200131)),
+            holidays.previous_open_day(Date(20200204))
+        );
+        assert_eq!(
+            vec![Date(20200131), Date(20200204)],
+            holidays.open_days(Date(20200131), Date(20200204))
+        );
+
+        assert_eq!(
+            5,
+            Calendar::AllDays
+                .open_days(Date(20200131), Date(20200204))
+                .len()
+        );
+        assert_eq!(
+            Some(Date(20200201)),
+            Calendar::AllDays.previous_open_day(Date(20200202))
+        );
+
+        let dates = Calendar::Dates(vec![Date(20200105), Date(20200110)].into_iter().collect());
+        assert_eq!(
+            Some(Date(20200105)),
+            dates.previous_open_day(Date(20200110))
+        );
+        assert_eq!(None, dates.previous_open_day(Date(20200105)));
+
+        // Walking back stops at the first day of year 1, rather than going on into years that can't be written.
+        assert_eq!(
+            Some(FIRST_DATE),
+            Calendar::AllDays.previous_open_day(FIRST_DATE.next_day())
+        );
+        assert_eq!(None, Calendar::AllDays.previous_open_day(FIRST_DATE));
+        assert_eq!(None, Calendar::AllDays.previous_open_day(Date(0)));
+        assert_eq!(None, weekdays.previous_open_day(FIRST_DATE));
+        assert_eq!(
+            vec![Date(20200110)],
+            dates.open_days(Date(20200106), Date(20200131))
+        );
+    }
+
+    #[test]
+    fn test_previous_day() {
+        assert_eq!(Date(20200314), Date(20200315).previous_day());
+        assert_eq!(Date(20200229), Date(20200301).previous_day());
+        assert_eq!(Date(20210228), Date(20210301).previous_day());
+        assert_eq!(Date(20201130), Date(20201201).previous_day());
+        assert_eq!(Date(20191231), Date(20200101).previous_day());
+    }
+}
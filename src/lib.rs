@@ -1,3 +1,2 @@
 pub mod btree;
-
-use crate::btree::file::read_csv;
+pub mod date;
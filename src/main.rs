@@ -1,33 +1,49 @@
-use findb::btree::file::{read_csv, BTree, Query};
+use findb::btree::{read_csv, AssetId, BTree, Date, Key, Query, Timestamp, Value};
+use findb::date::last_day_of_month;
+use std::env;
 use std::fs::File;
-use std::time;
-use std::time::UNIX_EPOCH;
 
-fn main() {
-    // let mut iterator = read_csv("volume-APPL-IBM-GOOG-2020.csv");
-    // BTree::write_from_iterator("volume-APPL-IBM-GOOG-2020.db", 1024, &mut iterator).unwrap();
+const DB_FILE: &str = "findb-sample.db";
+const PAGE_SIZE: u32 = 1024;
 
-    // let file = File::open("volume-APPL-IBM-GOOG-2020.db").unwrap();
-    // let mut btree = BTree::from_file(file).unwrap();
-    // let iterator = btree.query(Query {
-    //     id: 0,
-    //     asset_id: 1,
-    //     start_date: 20201001,
-    //     end_date: 20201031,
-    //     timestamp: time::SystemTime::now()
-    //         .duration_since(UNIX_EPOCH)
-    //         .unwrap()
-    //         .as_secs() as u32,
-    // });
-    //
-    // for result in iterator.unwrap() {
-    //     println!("{:?}", result.unwrap())
-    // }
-}
+/// Loads a CSV of `asset_id,date,timestamp,value` rows into a BTree file, or a generated year of daily values for three
+/// assets if no CSV is given, then prints asset 1's values for October 2020.
+///
+/// Usage: findb [csv_file]
+fn main() -> std::io::Result<()> {
+    match env::args().nth(1) {
+        Some(csv_file) => {
+            let rows = read_csv(&csv_file)?.collect::<std::io::Result<Vec<_>>>()?;
+            BTree::write_from_iterator(DB_FILE, PAGE_SIZE, &mut rows.into_iter())?;
+        }
+        None => BTree::write_from_iterator(DB_FILE, PAGE_SIZE, &mut sample_rows())?,
+    }
 
-fn print_result(res: Result<usize, usize>) {
-    match res {
-        Ok(v) => println!("Ok({})", v),
-        Err(v) => println!("Err({})", v),
+    let mut btree = BTree::from_file(File::open(DB_FILE)?, 16)?;
+    let results = btree.query(Query::new(
+        0,
+        AssetId(1),
+        Date(20201001),
+        Date(20201031),
+        Timestamp(u64::MAX),
+    ))?;
+    for result in results {
+        println!("{:?}", result?);
     }
+    Ok(())
+}
+
+fn sample_rows() -> impl Iterator<Item = (Key, Value)> {
+    (1..=3).flat_map(|asset_id| {
+        (1..=12).flat_map(move |month| {
+            (1..=last_day_of_month(2020, month)).map(move |day| {
+                let key = Key::new(
+                    AssetId(asset_id),
+                    Date::from_ymd(2020, month, day),
+                    Timestamp(1),
+                );
+                (key, (asset_id * 100 + day) as Value)
+            })
+        })
+    })
 }